@@ -0,0 +1,759 @@
+// Static control-flow analysis of ROMs: walks the code reachable from the
+// entry point and flags likely bugs. Self-modifying code and computed jumps
+// (BNNN) can't be followed, so the results are a best effort. SUPER-CHIP and
+// XO-CHIP instructions are flagged but walked past like ordinary ones.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::chip8::{Instruction, PROGRAM_LOAD_ADDRESS, RAM_SIZE, STACK_DEPTH};
+use crate::disasm;
+
+const ENTRY_POINT: u16 = PROGRAM_LOAD_ADDRESS as u16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Error,
+    Warning,
+    // Fine on some interpreters, surprising on others
+    Info,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Issue {
+    pub address: u16,
+    pub severity: Severity,
+    pub message: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EdgeKind {
+    Next,
+    Jump,
+    // Taken when a skip instruction skips
+    Skip,
+    Call,
+}
+
+#[derive(Clone, Debug)]
+struct Node {
+    opcode: u16,
+    successors: Vec<(u16, EdgeKind)>,
+}
+
+pub struct Analysis {
+    // Reachable instructions by address
+    nodes: BTreeMap<u16, Node>,
+    // Subroutine entry points and the subroutines they call
+    calls: BTreeMap<u16, BTreeSet<u16>>,
+    // Subroutines that can end up calling themselves
+    recursive: BTreeSet<u16>,
+    issues: Vec<Issue>,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Info => "info",
+        };
+        write!(f, "{:#05x}: {}: {}", self.address, severity, self.message)
+    }
+}
+
+// Analyzes a ROM loaded at PROGRAM_LOAD_ADDRESS, which has to fit in memory
+pub fn analyze(rom: &[u8]) -> Result<Analysis, String> {
+    let capacity = RAM_SIZE - PROGRAM_LOAD_ADDRESS;
+    if rom.len() > capacity {
+        return Err(format!(
+            "ROM too big: {} bytes, memory holds {}",
+            rom.len(),
+            capacity
+        ));
+    }
+
+    let mut analysis = Analysis {
+        nodes: BTreeMap::new(),
+        calls: BTreeMap::new(),
+        recursive: BTreeSet::new(),
+        issues: Vec::new(),
+    };
+    let end = PROGRAM_LOAD_ADDRESS + rom.len();
+    let fetch = |address: u16| {
+        let offset = (address as usize).checked_sub(PROGRAM_LOAD_ADDRESS)?;
+        let bytes = rom.get(offset..offset + 2)?;
+        Some((bytes[0] as u16) << 8 | bytes[1] as u16)
+    };
+
+    // Walk each subroutine separately, so calls can be attributed to the
+    // subroutine they are made from
+    let mut data_references = BTreeSet::new();
+    let mut functions = vec![ENTRY_POINT];
+    while let Some(function) = functions.pop() {
+        if analysis.calls.contains_key(&function) {
+            continue;
+        }
+        let mut callees = BTreeSet::new();
+        let mut visited = BTreeSet::new();
+        let mut worklist = vec![function];
+        while let Some(address) = worklist.pop() {
+            if !visited.insert(address) {
+                continue;
+            }
+            let Some(opcode) = fetch(address) else {
+                analysis.issue(
+                    address,
+                    Severity::Error,
+                    "execution runs off the end of the ROM",
+                );
+                continue;
+            };
+            // Skips need the size of the instruction they skip
+            let skipped = fetch(address + 2).map_or(2, size);
+            let successors = analysis.inspect(address, opcode, skipped, end);
+            if opcode & 0xF000 == 0xA000 {
+                data_references.insert(opcode & 0x0FFF);
+            }
+            for &(target, kind) in &successors {
+                if kind == EdgeKind::Call {
+                    callees.insert(target);
+                    functions.push(target);
+                } else {
+                    worklist.push(target);
+                }
+            }
+            analysis
+                .nodes
+                .entry(address)
+                .or_insert(Node { opcode, successors });
+        }
+        analysis.calls.insert(function, callees);
+    }
+
+    analysis.recursive = find_recursion(&analysis.calls);
+    analysis.check_stack_depth();
+    analysis.check_unreachable(rom.len(), &data_references);
+    // Instructions shared by subroutines get inspected once per subroutine
+    analysis.issues.sort();
+    analysis.issues.dedup();
+    Ok(analysis)
+}
+
+impl Analysis {
+    pub fn issues(&self) -> &[Issue] {
+        &self.issues
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.issues
+            .iter()
+            .any(|issue| issue.severity == Severity::Error)
+    }
+
+    pub fn is_reachable(&self, address: u16) -> bool {
+        self.nodes.contains_key(&address)
+    }
+
+    // Reachable instructions with their opcodes, by address
+    pub fn instructions(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        self.nodes
+            .iter()
+            .map(|(&address, node)| (address, node.opcode))
+    }
+
+    pub fn opcode(&self, address: u16) -> Option<u16> {
+        self.nodes.get(&address).map(|node| node.opcode)
+    }
+
+    // Where execution can continue after the reachable instruction at the
+    // address, including into subroutines it calls
+    pub fn successors(&self, address: u16) -> impl Iterator<Item = u16> + '_ {
+        self.nodes
+            .get(&address)
+            .into_iter()
+            .flat_map(|node| node.successors.iter().map(|&(target, _)| target))
+    }
+
+    // How often each opcode class appears in the reachable code
+    pub fn opcode_histogram(&self) -> BTreeMap<&'static str, usize> {
+        let mut histogram = BTreeMap::new();
+        for node in self.nodes.values() {
+            *histogram
+                .entry(disasm::opcode_class(node.opcode))
+                .or_default() += 1;
+        }
+        histogram
+    }
+
+    // Deepest nesting of subroutine calls, None if subroutines recurse
+    pub fn max_call_depth(&self) -> Option<usize> {
+        self.call_depth(ENTRY_POINT, &mut BTreeMap::new())
+    }
+
+    // Writes the control-flow graph in Graphviz' DOT format, with one node
+    // per basic block. Calls are dashed, taken skips dotted.
+    pub fn write_dot(&self, mut writer: impl Write) -> io::Result<()> {
+        writeln!(writer, "digraph rom {{")?;
+        writeln!(writer, "    node [shape=box, fontname=\"monospace\"];")?;
+        for (leader, block) in self.basic_blocks() {
+            let label: String = block
+                .iter()
+                .map(|address| {
+                    let opcode = self.nodes[address].opcode;
+                    format!("{:#05x}: {}\\l", address, disasm::disassemble(opcode))
+                })
+                .collect();
+            writeln!(writer, "    \"{:#05x}\" [label=\"{}\"];", leader, label)?;
+
+            let last = block.last().unwrap_or(&leader);
+            for (target, kind) in &self.nodes[last].successors {
+                let style = match kind {
+                    EdgeKind::Next | EdgeKind::Jump => "solid",
+                    EdgeKind::Skip => "dotted",
+                    EdgeKind::Call => "dashed",
+                };
+                writeln!(
+                    writer,
+                    "    \"{:#05x}\" -> \"{:#05x}\" [style={}];",
+                    leader, target, style
+                )?;
+            }
+        }
+        writeln!(writer, "}}")
+    }
+
+    fn issue(&mut self, address: u16, severity: Severity, message: impl Into<String>) {
+        self.issues.push(Issue {
+            address,
+            severity,
+            message: message.into(),
+        });
+    }
+
+    // Flags problems with a single instruction and returns where execution
+    // can continue
+    fn inspect(
+        &mut self,
+        address: u16,
+        opcode: u16,
+        skipped: u16,
+        end: usize,
+    ) -> Vec<(u16, EdgeKind)> {
+        let instruction = Instruction::from(opcode);
+        let next = address + size(opcode);
+        let class = disasm::opcode_class(opcode);
+        if let Some(quirk) = quirk(class) {
+            self.issue(address, Severity::Info, format!("{} {}", class, quirk));
+        }
+
+        match class {
+            "00EE" => vec![],
+            "1NNN" | "2NNN" => {
+                let target = instruction.nnn();
+                // Targets outside of the ROM are only reported here, walking
+                // them would report them again
+                let in_rom = self.check_target(address, target, end);
+                match (class, in_rom) {
+                    ("1NNN", true) => vec![(target, EdgeKind::Jump)],
+                    ("1NNN", false) => vec![],
+                    (_, true) => vec![(target, EdgeKind::Call), (next, EdgeKind::Next)],
+                    (_, false) => vec![(next, EdgeKind::Next)],
+                }
+            }
+            "3XNN" | "4XNN" | "5XY0" | "9XY0" | "EX9E" | "EXA1" => {
+                vec![(next, EdgeKind::Next), (next + skipped, EdgeKind::Skip)]
+            }
+            "BNNN" => {
+                self.issue(
+                    address,
+                    Severity::Warning,
+                    "computed jump, the targets can't be followed",
+                );
+                vec![]
+            }
+            "invalid" | "0NNN" => {
+                self.issue(
+                    address,
+                    Severity::Error,
+                    format!("{:04X} is not a supported instruction", opcode),
+                );
+                vec![]
+            }
+            "00CN" | "00DN" | "00FB" | "00FC" | "00FD" | "00FE" | "00FF" | "5XY2" | "5XY3"
+            | "F000" | "FX01" | "FX30" | "FX75" | "FX85" => {
+                self.issue(
+                    address,
+                    Severity::Error,
+                    format!("{:04X} is a SUPER-CHIP or XO-CHIP instruction", opcode),
+                );
+                // 00FD exits the interpreter
+                if class == "00FD" {
+                    vec![]
+                } else {
+                    vec![(next, EdgeKind::Next)]
+                }
+            }
+            _ => vec![(next, EdgeKind::Next)],
+        }
+    }
+
+    // Returns whether the target is in the ROM
+    fn check_target(&mut self, address: u16, target: u16, end: usize) -> bool {
+        if !target.is_multiple_of(2) {
+            self.issue(
+                address,
+                Severity::Error,
+                format!("odd-aligned jump to {:#05x}", target),
+            );
+        }
+        let in_rom = (PROGRAM_LOAD_ADDRESS..end).contains(&(target as usize));
+        if !in_rom {
+            self.issue(
+                address,
+                Severity::Error,
+                format!("jump to {:#05x} outside of the ROM", target),
+            );
+        }
+        in_rom
+    }
+
+    fn check_stack_depth(&mut self) {
+        for function in self.recursive.clone() {
+            self.issue(
+                function,
+                Severity::Warning,
+                "recursive subroutine, calls can exceed the stack depth",
+            );
+        }
+        if let Some(depth) = self.max_call_depth()
+            && depth > STACK_DEPTH
+        {
+            self.issue(
+                ENTRY_POINT,
+                Severity::Error,
+                format!(
+                    "subroutine calls nest {} deep, the stack only holds {}",
+                    depth, STACK_DEPTH
+                ),
+            );
+        }
+    }
+
+    // Each subroutine's depth is worked out once, so subroutines called from
+    // many places don't get walked again for every caller
+    fn call_depth(
+        &self,
+        function: u16,
+        depths: &mut BTreeMap<u16, Option<usize>>,
+    ) -> Option<usize> {
+        if self.recursive.contains(&function) {
+            return None;
+        }
+        if let Some(&depth) = depths.get(&function) {
+            return depth;
+        }
+        let depth = self
+            .calls
+            .get(&function)
+            .into_iter()
+            .flatten()
+            .try_fold(0, |depth, &callee| {
+                Some(depth.max(self.call_depth(callee, depths)? + 1))
+            });
+        depths.insert(function, depth);
+        depth
+    }
+
+    // Reports runs of bytes no reachable instruction covers. Runs that an
+    // LD I, addr points into are taken to be data.
+    fn check_unreachable(&mut self, rom_size: usize, data_references: &BTreeSet<u16>) {
+        let mut covered = vec![false; rom_size];
+        for (&address, node) in &self.nodes {
+            let offset = address as usize - PROGRAM_LOAD_ADDRESS;
+            let size = size(node.opcode) as usize;
+            for byte in covered.iter_mut().skip(offset).take(size) {
+                *byte = true;
+            }
+        }
+
+        let mut offset = 0;
+        while offset < rom_size {
+            if covered[offset] {
+                offset += 1;
+                continue;
+            }
+            let start = offset;
+            while offset < rom_size && !covered[offset] {
+                offset += 1;
+            }
+            let first = (PROGRAM_LOAD_ADDRESS + start) as u16;
+            let last = (PROGRAM_LOAD_ADDRESS + offset - 1) as u16;
+            if data_references.range(first..=last).next().is_none() {
+                self.issue(
+                    first,
+                    Severity::Warning,
+                    format!("unreachable code or unused data up to {:#05x}", last),
+                );
+            }
+        }
+    }
+
+    // Maximal straight-line runs of instructions, by their first address
+    fn basic_blocks(&self) -> BTreeMap<u16, Vec<u16>> {
+        let mut predecessors: BTreeMap<u16, usize> = BTreeMap::new();
+        for node in self.nodes.values() {
+            for (target, _) in &node.successors {
+                *predecessors.entry(*target).or_default() += 1;
+            }
+        }
+        let falls_through = |address: &u16| {
+            let successors = &self.nodes[address].successors;
+            successors.len() == 1 && successors[0].1 == EdgeKind::Next
+        };
+        let is_leader = |address: &u16| {
+            *address == ENTRY_POINT || predecessors.get(address) != Some(&1) || {
+                // A single predecessor that doesn't simply fall through
+                !self
+                    .nodes
+                    .range(..*address)
+                    .next_back()
+                    .is_some_and(|(previous, node)| {
+                        *previous + size(node.opcode) == *address && falls_through(previous)
+                    })
+            }
+        };
+
+        let mut blocks = BTreeMap::new();
+        for leader in self.nodes.keys().filter(|address| is_leader(address)) {
+            let mut block = vec![*leader];
+            let mut address = *leader;
+            while falls_through(&address) {
+                let next = address + size(self.nodes[&address].opcode);
+                if !self.nodes.contains_key(&next) || is_leader(&next) {
+                    break;
+                }
+                block.push(next);
+                address = next;
+            }
+            blocks.insert(*leader, block);
+        }
+        blocks
+    }
+}
+
+// Subroutines in a cycle of the call graph, found as the strongly connected
+// components with more than one subroutine or a subroutine calling itself
+// (Tarjan's algorithm)
+fn find_recursion(calls: &BTreeMap<u16, BTreeSet<u16>>) -> BTreeSet<u16> {
+    struct Search<'a> {
+        calls: &'a BTreeMap<u16, BTreeSet<u16>>,
+        // Visiting order, and the lowest one reachable through the subroutines
+        // still on the stack
+        index: BTreeMap<u16, usize>,
+        low_link: BTreeMap<u16, usize>,
+        stack: Vec<u16>,
+        on_stack: BTreeSet<u16>,
+        recursive: BTreeSet<u16>,
+    }
+
+    impl Search<'_> {
+        fn visit(&mut self, function: u16) {
+            let index = self.index.len();
+            self.index.insert(function, index);
+            self.low_link.insert(function, index);
+            self.stack.push(function);
+            self.on_stack.insert(function);
+
+            let callees = self.calls.get(&function).into_iter().flatten();
+            for &callee in callees {
+                let reached = if !self.index.contains_key(&callee) {
+                    self.visit(callee);
+                    self.low_link[&callee]
+                } else if self.on_stack.contains(&callee) {
+                    self.index[&callee]
+                } else {
+                    continue;
+                };
+                let low_link = self.low_link[&function].min(reached);
+                self.low_link.insert(function, low_link);
+            }
+
+            if self.low_link[&function] == index {
+                let start = self.stack.iter().rposition(|&f| f == function).unwrap_or(0);
+                let component = self.stack.split_off(start);
+                for member in &component {
+                    self.on_stack.remove(member);
+                }
+                let calls_itself = self
+                    .calls
+                    .get(&function)
+                    .is_some_and(|callees| callees.contains(&function));
+                if component.len() > 1 || calls_itself {
+                    self.recursive.extend(component);
+                }
+            }
+        }
+    }
+
+    let mut search = Search {
+        calls,
+        index: BTreeMap::new(),
+        low_link: BTreeMap::new(),
+        stack: Vec::new(),
+        on_stack: BTreeSet::new(),
+        recursive: BTreeSet::new(),
+    };
+    for &function in calls.keys() {
+        if !search.index.contains_key(&function) {
+            search.visit(function);
+        }
+    }
+    search.recursive
+}
+
+// XO-CHIP's F000 NNNN is the only instruction with four bytes
+fn size(opcode: u16) -> u16 {
+    if opcode == 0xF000 { 4 } else { 2 }
+}
+
+// Behaviour that differs between CHIP-8 interpreters
+fn quirk(class: &str) -> Option<&'static str> {
+    match class {
+        "8XY1" | "8XY2" | "8XY3" => Some("resets VF on the COSMAC VIP only"),
+        "8XY6" | "8XYE" => Some("shifts Vy on the COSMAC VIP, Vx on CHIP-48/SUPER-CHIP"),
+        "BNNN" => Some("jumps relative to VX instead of V0 on CHIP-48/SUPER-CHIP"),
+        "FX55" | "FX65" => Some("increments I on the COSMAC VIP only"),
+        "FX1E" => Some("sets VF on overflow on some interpreters"),
+        "FX0A" => Some("finishes on key release on the COSMAC VIP, on press elsewhere"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(analysis: &Analysis, severity: Severity) -> Vec<String> {
+        analysis
+            .issues()
+            .iter()
+            .filter(|issue| issue.severity == severity)
+            .map(|issue| issue.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn it_follows_jumps_calls_and_skips() {
+        let rom = [
+            0x22, 0x08, // 0x200: CALL 0x208
+            0x30, 0x01, // 0x202: SE V0, 1
+            0x12, 0x02, // 0x204: JP 0x202
+            0x12, 0x06, // 0x206: JP 0x206
+            0x60, 0x01, // 0x208: LD V0, 1
+            0x00, 0xEE, // 0x20A: RET
+        ];
+        let analysis = analyze(&rom).unwrap();
+        assert!(analysis.issues().is_empty(), "{:?}", analysis.issues());
+        assert!((0x200..0x20C).step_by(2).all(|a| analysis.is_reachable(a)));
+        assert_eq!(analysis.max_call_depth(), Some(1));
+        assert_eq!(
+            analysis.opcode_histogram(),
+            BTreeMap::from([
+                ("00EE", 1),
+                ("1NNN", 2),
+                ("2NNN", 1),
+                ("3XNN", 1),
+                ("6XNN", 1)
+            ])
+        );
+    }
+
+    #[test]
+    fn it_flags_broken_code() {
+        let rom = [
+            0x12, 0x03, // 0x200: JP 0x203
+            0xFF, // 0x202: never reached
+            0x80, 0x16, // 0x203: SHR V0, V1
+            0xB2, 0x00, // 0x205: JP V0, 0x200
+        ];
+        let analysis = analyze(&rom).unwrap();
+        assert!(analysis.has_errors());
+        assert_eq!(
+            messages(&analysis, Severity::Error),
+            ["0x200: error: odd-aligned jump to 0x203"]
+        );
+        assert_eq!(
+            messages(&analysis, Severity::Warning),
+            [
+                "0x202: warning: unreachable code or unused data up to 0x202",
+                "0x205: warning: computed jump, the targets can't be followed",
+            ]
+        );
+        assert_eq!(messages(&analysis, Severity::Info).len(), 2);
+    }
+
+    #[test]
+    fn it_flags_unsupported_opcodes_and_running_off_the_rom() {
+        // LD V1, 0; SYS 0x123
+        let analysis = analyze(&[0x61, 0x00, 0x01, 0x23]).unwrap();
+        assert_eq!(
+            messages(&analysis, Severity::Error),
+            ["0x202: error: 0123 is not a supported instruction"]
+        );
+        // CLS, then nothing
+        let analysis = analyze(&[0x00, 0xE0]).unwrap();
+        assert_eq!(
+            messages(&analysis, Severity::Error),
+            ["0x202: error: execution runs off the end of the ROM"]
+        );
+    }
+
+    #[test]
+    fn it_walks_past_super_chip_and_xo_chip_instructions() {
+        let rom = [
+            0xF0, 0x00, 0x12, 0x34, // 0x200: LD I, long 0x1234
+            0x30, 0x00, // 0x204: SE V0, 0
+            0xF0, 0x00, 0x12, 0x34, // 0x206: LD I, long 0x1234
+            0x12, 0x0A, // 0x20A: JP 0x20A
+        ];
+        let analysis = analyze(&rom).unwrap();
+        assert_eq!(
+            messages(&analysis, Severity::Error),
+            [
+                "0x200: error: F000 is a SUPER-CHIP or XO-CHIP instruction",
+                "0x206: error: F000 is a SUPER-CHIP or XO-CHIP instruction",
+            ]
+        );
+        assert!(messages(&analysis, Severity::Warning).is_empty());
+        assert!(!analysis.is_reachable(0x202));
+        assert_eq!(
+            analysis.successors(0x204).collect::<Vec<_>>(),
+            [0x206, 0x20A]
+        );
+        assert_eq!(
+            analysis.opcode_histogram(),
+            BTreeMap::from([("1NNN", 1), ("3XNN", 1), ("F000", 2)])
+        );
+    }
+
+    #[test]
+    fn it_flags_recursion_and_deep_calls() {
+        // CALL 0x202; CALL 0x202
+        let analysis = analyze(&[0x22, 0x02, 0x22, 0x02]).unwrap();
+        assert_eq!(analysis.max_call_depth(), None);
+        assert_eq!(
+            messages(&analysis, Severity::Warning),
+            ["0x202: warning: recursive subroutine, calls can exceed the stack depth"]
+        );
+
+        // A chain of subroutines, each calling the next
+        let mut rom = Vec::new();
+        for i in 0..=STACK_DEPTH as u16 {
+            let next = 0x204 + i * 4;
+            rom.extend_from_slice(&[0x20 | (next >> 8) as u8, next as u8, 0x00, 0xEE]);
+        }
+        rom.extend_from_slice(&[0x00, 0xEE]);
+        let analysis = analyze(&rom).unwrap();
+        assert_eq!(analysis.max_call_depth(), Some(STACK_DEPTH + 1));
+        assert!(analysis.has_errors());
+    }
+
+    #[test]
+    fn it_finds_every_subroutine_in_a_recursion() {
+        let rom = [
+            0x22, 0x04, // 0x200: CALL 0x204
+            0x12, 0x02, // 0x202: JP 0x202
+            0x22, 0x0A, // 0x204: CALL 0x20A
+            0x22, 0x0E, // 0x206: CALL 0x20E
+            0x00, 0xEE, // 0x208: RET
+            0x22, 0x04, // 0x20A: CALL 0x204
+            0x00, 0xEE, // 0x20C: RET
+            0x22, 0x0A, // 0x20E: CALL 0x20A
+            0x00, 0xEE, // 0x210: RET
+        ];
+        let analysis = analyze(&rom).unwrap();
+        assert_eq!(analysis.max_call_depth(), None);
+        let recursive: Vec<u16> = analysis
+            .issues()
+            .iter()
+            .filter(|issue| issue.message.starts_with("recursive"))
+            .map(|issue| issue.address)
+            .collect();
+        assert_eq!(recursive, [0x204, 0x20A, 0x20E]);
+    }
+
+    #[test]
+    fn it_handles_subroutines_called_from_many_places() {
+        // Each subroutine calls the next one twice, which takes 2^40 walks
+        // without remembering depths
+        let mut rom = Vec::new();
+        for i in 0..40u16 {
+            let next = 0x206 + i * 6;
+            let call = [0x20 | (next >> 8) as u8, next as u8];
+            rom.extend_from_slice(&call);
+            rom.extend_from_slice(&call);
+            rom.extend_from_slice(&[0x00, 0xEE]);
+        }
+        rom.extend_from_slice(&[0x00, 0xEE]);
+        let analysis = analyze(&rom).unwrap();
+        assert_eq!(analysis.max_call_depth(), Some(40));
+    }
+
+    #[test]
+    fn it_reports_targets_outside_of_the_rom_once() {
+        // CALL 0x400; JP 0x202
+        let analysis = analyze(&[0x24, 0x00, 0x12, 0x02]).unwrap();
+        assert_eq!(
+            messages(&analysis, Severity::Error),
+            ["0x200: error: jump to 0x400 outside of the ROM"]
+        );
+    }
+
+    #[test]
+    fn it_reports_shared_instructions_once() {
+        let rom = [
+            0x22, 0x06, // 0x200: CALL 0x206
+            0x22, 0x08, // 0x202: CALL 0x208
+            0x12, 0x04, // 0x204: JP 0x204
+            0x60, 0x00, // 0x206: LD V0, 0
+            0xB2, 0x00, // 0x208: JP V0, 0x200
+        ];
+        let analysis = analyze(&rom).unwrap();
+        let at_0x208: Vec<_> = analysis
+            .issues()
+            .iter()
+            .filter(|issue| issue.address == 0x208)
+            .map(|issue| issue.severity)
+            .collect();
+        assert_eq!(at_0x208, [Severity::Warning, Severity::Info]);
+    }
+
+    #[test]
+    fn it_rejects_roms_that_dont_fit_in_memory() {
+        let rom = vec![0x12; RAM_SIZE - PROGRAM_LOAD_ADDRESS + 1];
+        assert!(analyze(&rom).is_err());
+        assert!(analyze(&rom[1..]).is_ok());
+    }
+
+    #[test]
+    fn it_treats_unreachable_bytes_referenced_by_i_as_data() {
+        // LD I, 0x204; JP 0x202; sprite
+        let analysis = analyze(&[0xA2, 0x04, 0x12, 0x02, 0xF0, 0x90]).unwrap();
+        assert!(analysis.issues().is_empty(), "{:?}", analysis.issues());
+    }
+
+    #[test]
+    fn it_exports_basic_blocks_as_dot() {
+        // LD V0, 1; SE V0, 1; JP 0x200; JP 0x206
+        let analysis = analyze(&[0x60, 0x01, 0x30, 0x01, 0x12, 0x00, 0x12, 0x06]).unwrap();
+        let mut dot = Vec::new();
+        analysis.write_dot(&mut dot).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.starts_with("digraph rom {"));
+        assert!(dot.contains("\"0x200\" [label=\"0x200: LD V0, 0x01\\l0x202: SE V0, 0x01\\l\"];"));
+        assert!(dot.contains("\"0x200\" -> \"0x206\" [style=dotted];"));
+        assert!(dot.contains("\"0x204\" -> \"0x200\" [style=solid];"));
+        assert!(dot.contains("\"0x206\" -> \"0x206\" [style=solid];"));
+    }
+}
@@ -0,0 +1,275 @@
+// Assembler for the syntax of Cowgod's CHIP-8 technical reference, which is
+// also what the disassembler writes. Lines hold an optional label ("loop:"),
+// an instruction and an optional comment after ";". Numbers are decimal, or
+// hex and binary with 0x and 0b prefixes. DB and DW emit data bytes and words.
+use std::collections::HashMap;
+
+use crate::chip8::PROGRAM_LOAD_ADDRESS;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operand {
+    V(u16),
+    I,
+    // [I], the memory I points to
+    IndirectI,
+    Dt,
+    St,
+    K,
+    F,
+    B,
+    Value(u16),
+}
+
+struct Statement<'a> {
+    line: usize,
+    mnemonic: String,
+    operands: Vec<&'a str>,
+}
+
+// Assembles a program to be loaded at PROGRAM_LOAD_ADDRESS. Errors name the
+// line they occurred on.
+pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+    // First pass: find the labels' addresses
+    let mut labels = HashMap::new();
+    let mut statements = Vec::new();
+    let mut address = PROGRAM_LOAD_ADDRESS;
+    for (index, line) in source.lines().enumerate() {
+        let line_number = index + 1;
+        let mut code = line.split(';').next().unwrap_or_default().trim();
+        while let Some((label, rest)) = code.split_once(':') {
+            let label = label.trim();
+            if !is_identifier(label) {
+                return Err(format!("line {}: invalid label: {}", line_number, label));
+            }
+            if labels
+                .insert(label.to_ascii_uppercase(), address as u16)
+                .is_some()
+            {
+                return Err(format!("line {}: duplicate label: {}", line_number, label));
+            }
+            code = rest.trim();
+        }
+        if code.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, operands) = code.split_once(char::is_whitespace).unwrap_or((code, ""));
+        let statement = Statement {
+            line: line_number,
+            mnemonic: mnemonic.to_ascii_uppercase(),
+            operands: operands
+                .split(',')
+                .map(str::trim)
+                .filter(|operand| !operand.is_empty())
+                .collect(),
+        };
+        address += match statement.mnemonic.as_str() {
+            "DB" => statement.operands.len(),
+            "DW" => statement.operands.len() * 2,
+            _ => 2,
+        };
+        statements.push(statement);
+    }
+
+    // Second pass: encode with all labels known
+    let mut output = Vec::new();
+    for statement in &statements {
+        encode(statement, &labels, &mut output)
+            .map_err(|e| format!("line {}: {}", statement.line, e))?;
+    }
+    Ok(output)
+}
+
+fn encode(
+    statement: &Statement,
+    labels: &HashMap<String, u16>,
+    output: &mut Vec<u8>,
+) -> Result<(), String> {
+    let operands = statement
+        .operands
+        .iter()
+        .map(|operand| parse_operand(operand, labels))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    use Operand::*;
+    let opcode = match (statement.mnemonic.as_str(), &operands[..]) {
+        ("DB", values) => {
+            for value in values {
+                output.push(value_in(*value, 0xFF)? as u8);
+            }
+            return Ok(());
+        }
+        ("DW", values) => {
+            for value in values {
+                output.extend(value_in(*value, 0xFFFF)?.to_be_bytes());
+            }
+            return Ok(());
+        }
+        ("CLS", []) => 0x00E0,
+        ("RET", []) => 0x00EE,
+        ("SYS", [a]) => address(*a)?,
+        ("JP", [a]) => 0x1000 | address(*a)?,
+        ("JP", [V(0), a]) => 0xB000 | address(*a)?,
+        ("CALL", [a]) => 0x2000 | address(*a)?,
+        ("SE", [V(x), V(y)]) => 0x5000 | x << 8 | y << 4,
+        ("SE", [V(x), b]) => 0x3000 | x << 8 | byte(*b)?,
+        ("SNE", [V(x), V(y)]) => 0x9000 | x << 8 | y << 4,
+        ("SNE", [V(x), b]) => 0x4000 | x << 8 | byte(*b)?,
+        ("LD", [V(x), V(y)]) => 0x8000 | x << 8 | y << 4,
+        ("LD", [V(x), Dt]) => 0xF007 | x << 8,
+        ("LD", [V(x), K]) => 0xF00A | x << 8,
+        ("LD", [V(x), IndirectI]) => 0xF065 | x << 8,
+        ("LD", [V(x), b]) => 0x6000 | x << 8 | byte(*b)?,
+        ("LD", [I, a]) => 0xA000 | address(*a)?,
+        ("LD", [Dt, V(x)]) => 0xF015 | x << 8,
+        ("LD", [St, V(x)]) => 0xF018 | x << 8,
+        ("LD", [F, V(x)]) => 0xF029 | x << 8,
+        ("LD", [B, V(x)]) => 0xF033 | x << 8,
+        ("LD", [IndirectI, V(x)]) => 0xF055 | x << 8,
+        ("ADD", [V(x), V(y)]) => 0x8004 | x << 8 | y << 4,
+        ("ADD", [V(x), b]) => 0x7000 | x << 8 | byte(*b)?,
+        ("ADD", [I, V(x)]) => 0xF01E | x << 8,
+        ("OR", [V(x), V(y)]) => 0x8001 | x << 8 | y << 4,
+        ("AND", [V(x), V(y)]) => 0x8002 | x << 8 | y << 4,
+        ("XOR", [V(x), V(y)]) => 0x8003 | x << 8 | y << 4,
+        ("SUB", [V(x), V(y)]) => 0x8005 | x << 8 | y << 4,
+        ("SUBN", [V(x), V(y)]) => 0x8007 | x << 8 | y << 4,
+        // Without VY, shift VX by itself, which works with both shift quirks
+        ("SHR", [V(x)]) => 0x8006 | x << 8 | x << 4,
+        ("SHR", [V(x), V(y)]) => 0x8006 | x << 8 | y << 4,
+        ("SHL", [V(x)]) => 0x800E | x << 8 | x << 4,
+        ("SHL", [V(x), V(y)]) => 0x800E | x << 8 | y << 4,
+        ("RND", [V(x), b]) => 0xC000 | x << 8 | byte(*b)?,
+        ("DRW", [V(x), V(y), n]) => 0xD000 | x << 8 | y << 4 | value_in(*n, 0xF)?,
+        ("SKP", [V(x)]) => 0xE09E | x << 8,
+        ("SKNP", [V(x)]) => 0xE0A1 | x << 8,
+        ("AUDIO", []) => 0xF002,
+        ("PITCH", [V(x)]) => 0xF03A | x << 8,
+        _ => {
+            return Err(format!(
+                "invalid instruction: {} {}",
+                statement.mnemonic,
+                statement.operands.join(", ")
+            ));
+        }
+    };
+    output.extend(opcode.to_be_bytes());
+    Ok(())
+}
+
+fn parse_operand(operand: &str, labels: &HashMap<String, u16>) -> Result<Operand, String> {
+    let upper = operand.to_ascii_uppercase();
+    let operand = match upper.as_str() {
+        "I" => Operand::I,
+        "[I]" => Operand::IndirectI,
+        "DT" => Operand::Dt,
+        "ST" => Operand::St,
+        "K" => Operand::K,
+        "F" => Operand::F,
+        "B" => Operand::B,
+        register if register.len() == 2 && register.starts_with('V') => {
+            match u16::from_str_radix(&register[1..], 16) {
+                Ok(x) => Operand::V(x),
+                Err(_) => return Err(format!("invalid register: {}", operand)),
+            }
+        }
+        _ => Operand::Value(parse_value(&upper, labels)?),
+    };
+    Ok(operand)
+}
+
+fn parse_value(value: &str, labels: &HashMap<String, u16>) -> Result<u16, String> {
+    let number = if let Some(hex) = value.strip_prefix("0X") {
+        u16::from_str_radix(hex, 16)
+    } else if let Some(binary) = value.strip_prefix("0B") {
+        u16::from_str_radix(binary, 2)
+    } else if value.starts_with(|c: char| c.is_ascii_digit()) {
+        value.parse()
+    } else {
+        return labels
+            .get(value)
+            .copied()
+            .ok_or_else(|| format!("unknown label: {}", value));
+    };
+    number.map_err(|_| format!("invalid number: {}", value))
+}
+
+fn value_in(operand: Operand, max: u16) -> Result<u16, String> {
+    match operand {
+        Operand::Value(value) if value <= max => Ok(value),
+        Operand::Value(value) => Err(format!("{:#x} is out of range (max {:#x})", value, max)),
+        _ => Err(format!("expected a number, got {:?}", operand)),
+    }
+}
+
+fn address(operand: Operand) -> Result<u16, String> {
+    value_in(operand, 0xFFF)
+}
+
+fn byte(operand: Operand) -> Result<u16, String> {
+    value_in(operand, 0xFF)
+}
+
+fn is_identifier(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm;
+
+    #[test]
+    fn it_assembles_programs_with_labels() {
+        let source = "
+            ; Draw a digit forever
+            start:  LD V0, 10
+                    LD F, V0
+                    DRW V1, V2, 5   ; draw it
+            loop:   JP loop
+            sprite: DB 0b11110000, 0x90
+                    DW sprite
+        ";
+        assert_eq!(
+            assemble(source).unwrap(),
+            [
+                0x60, 0x0A, 0xF0, 0x29, 0xD1, 0x25, 0x12, 0x06, 0xF0, 0x90, 0x02, 0x08
+            ]
+        );
+    }
+
+    #[test]
+    fn it_reassembles_disassembled_roms() {
+        for rom in [
+            &include_bytes!("../roms/BC_test.ch8")[..],
+            include_bytes!("../roms/test_opcode.ch8"),
+            include_bytes!("../roms/chip8-test-rom-with-audio.ch8"),
+        ] {
+            let mut listing = Vec::new();
+            disasm::write_listing(&mut listing, rom).unwrap();
+            let listing = String::from_utf8(listing).unwrap();
+            assert_eq!(assemble(&listing).unwrap(), rom);
+        }
+    }
+
+    #[test]
+    fn it_reports_errors_with_line_numbers() {
+        assert_eq!(
+            assemble("CLS\nJP nowhere").unwrap_err(),
+            "line 2: unknown label: NOWHERE"
+        );
+        assert_eq!(
+            assemble("LD V0, 0x100").unwrap_err(),
+            "line 1: 0x100 is out of range (max 0xff)"
+        );
+        assert_eq!(
+            assemble("a: CLS\na: RET").unwrap_err(),
+            "line 2: duplicate label: a"
+        );
+        assert_eq!(
+            assemble("MOV V0, V1").unwrap_err(),
+            "line 1: invalid instruction: MOV V0, V1"
+        );
+    }
+}
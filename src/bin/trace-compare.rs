@@ -0,0 +1,84 @@
+// Runs a ROM in lockstep with a reference trace and reports the first
+// instruction where the emulator's state differs. See src/trace.rs for the
+// trace line format.
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::process;
+
+use clap::Parser;
+
+use chip_8::chip8::{self, Chip8, SilentSpeaker};
+use chip_8::quirks::{self, Quirks};
+use chip_8::romdb::RomDatabase;
+use chip_8::trace::{self, Comparison};
+
+#[derive(Parser)]
+#[command(
+    name = "trace-compare",
+    about = "Run a ROM in lockstep with a reference trace and report the first difference",
+    after_help = "Quirks: key-wait-on-press, shift-vy, load-store-i, jump-vx, logic-vf, clip"
+)]
+struct Cli {
+    #[arg(value_name = "ROM")]
+    rom_path: PathBuf,
+    #[arg(
+        value_name = "TRACE",
+        help = "Reference trace in the --trace line format"
+    )]
+    trace_path: PathBuf,
+    #[arg(long, value_name = "n", default_value_t = chip8::TICKS_PER_FRAME,
+          value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+          help = "Instructions per timer update")]
+    ticks: usize,
+    #[arg(
+        long,
+        value_name = "id",
+        help = "Use the quirks of a platform from the ROM database, e.g. chip48"
+    )]
+    platform: Option<String>,
+    #[arg(long = "quirk", value_name = "name[=on|off]", value_parser = quirks::parse_quirk,
+          help = "Turn a quirk on or off, on top of the platform's")]
+    quirks: Vec<(String, bool)>,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    process::exit(match run(&cli) {
+        Ok(Comparison::Match(instructions)) => {
+            println!("Traces match for {} instructions", instructions);
+            0
+        }
+        Ok(Comparison::Divergence(divergence)) => {
+            print!("{}", divergence);
+            1
+        }
+        Err(err) => {
+            eprintln!("ERROR: {}", err);
+            2
+        }
+    });
+}
+
+// The ROM database isn't consulted, the quirks the reference trace was
+// recorded with have to be given
+fn run(cli: &Cli) -> Result<Comparison, String> {
+    let rom = fs::read(&cli.rom_path).map_err(|e| format!("Cannot read ROM: {}", e))?;
+    let reference =
+        File::open(&cli.trace_path).map_err(|e| format!("Cannot read reference trace: {}", e))?;
+
+    let mut quirks = match &cli.platform {
+        Some(platform) => RomDatabase::bundled()
+            .platform_quirks(platform)
+            .ok_or_else(|| format!("Unknown platform: {}", platform))?,
+        None => Quirks::default(),
+    };
+    for (name, enabled) in &cli.quirks {
+        quirks.set(name, *enabled)?;
+    }
+
+    let mut chip8 = Chip8::new(Box::new(SilentSpeaker));
+    chip8.load_rom(rom)?;
+    chip8.set_quirks(quirks);
+    trace::compare(&mut chip8, BufReader::new(reference), cli.ticks)
+}
@@ -1,6 +1,26 @@
+use std::collections::VecDeque;
+use std::path::Path;
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::coverage::Coverage;
+use crate::keypad::Key;
+use crate::palette::Palette;
+use crate::profiler::Profiler;
+use crate::quirks::Quirks;
+use crate::screenshot;
+use crate::trace::{TraceEntry, Tracer};
+
 pub const DISPLAY_WIDTH: usize = 64;
 pub const DISPLAY_HEIGHT: usize = 32;
 pub const TICKS_PER_FRAME: usize = 10;
+pub const FRAME_DURATION: Duration = Duration::from_micros(16667); // ~60 Hz
+
+pub const AUDIO_PATTERN_SIZE: usize = 16;
+// XO-CHIP pitch register value that plays patterns at 4000 bits per second
+const DEFAULT_AUDIO_PITCH: u8 = 64;
 
 const DEFAULT_CHARACTER_SET_SIZE: usize = 80;
 const DEFAULT_CHARACTER_SET: [u8; DEFAULT_CHARACTER_SET_SIZE] = [
@@ -24,12 +44,64 @@ const DEFAULT_CHARACTER_SET: [u8; DEFAULT_CHARACTER_SET_SIZE] = [
 const INSTRUCTION_LENGTH: u16 = 2;
 const NUM_DATA_REGISTERS: usize = 16;
 pub const NUM_KEYS: usize = 16;
-const PROGRAM_LOAD_ADDRESS: usize = 0x200;
-const RAM_SIZE: usize = 4096;
-const STACK_DEPTH: usize = 16;
+pub const PROGRAM_LOAD_ADDRESS: usize = 0x200;
+pub const RAM_SIZE: usize = 4096;
+pub const STACK_DEPTH: usize = 16;
 
 pub trait Speaker {
     fn beep(&mut self, status: bool);
+
+    // XO-CHIP audio: from now on play the 128 1-bit samples of `pattern`
+    // (most significant bit first) at `rate` samples per second while the
+    // sound timer is active. Speakers that can't play patterns keep beeping.
+    fn set_pattern(&mut self, _pattern: &[u8; AUDIO_PATTERN_SIZE], _rate: f32) {}
+}
+
+// A speaker that stays quiet, for running ROMs without sound output
+pub struct SilentSpeaker;
+
+impl Speaker for SilentSpeaker {
+    fn beep(&mut self, _status: bool) {}
+}
+
+// Playback rate of XO-CHIP audio patterns for a pitch register value
+pub fn audio_pattern_rate(pitch: u8) -> f32 {
+    4000.0 * 2f32.powf((pitch as f32 - 64.0) / 48.0)
+}
+
+// Screen updates caused by CLS and DRW. The rectangle of a draw event starts
+// at the (wrapped) sprite origin and may extend past the screen edges.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisplayEvent {
+    Clear,
+    Draw {
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        collision: bool,
+    },
+}
+
+type DisplayObserver<'a> = Box<dyn FnMut(DisplayEvent) + 'a>;
+
+// A key press or release reported by a frontend. The timestamp is in any
+// monotonic unit the frontend likes (e.g. milliseconds) and determines the
+// order in which queued events are applied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: Key,
+    pub pressed: bool,
+    pub timestamp: u64,
+}
+
+type InputObserver<'a> = Box<dyn FnMut(KeyEvent) + 'a>;
+
+// State of an FX0A instruction waiting for a key to be pressed and released
+#[derive(Clone, Copy, Debug)]
+struct KeyWait {
+    register: usize,
+    pressed: Option<Key>,
 }
 
 pub struct Chip8<'a> {
@@ -41,8 +113,28 @@ pub struct Chip8<'a> {
     sp: u8,
     dt: u8,
     st: u8,
+    sound_active: bool,
+    audio_pattern: [u8; AUDIO_PATTERN_SIZE],
+    audio_pitch: u8,
     keyboard: [bool; NUM_KEYS],
-    screen: [[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+    key_wait: Option<KeyWait>,
+    input_queue: VecDeque<KeyEvent>,
+    // First frame in which each key pressed through the queue may be released
+    release_frame: [Option<u64>; NUM_KEYS],
+    frames: u64,
+    // Whether an instruction has run since the timers were last updated
+    mid_frame: bool,
+    input_observer: Option<InputObserver<'a>>,
+    quirks: Quirks,
+    screen: [bool; DISPLAY_WIDTH * DISPLAY_HEIGHT],
+    screen_dirty: bool,
+    display_observer: Option<DisplayObserver<'a>>,
+    tracer: Option<Tracer<'a>>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    rom_size: usize,
+    // Source of CXNN's random numbers
+    rng: StdRng,
     speaker: Box<dyn Speaker + 'a>,
 }
 
@@ -58,8 +150,25 @@ impl<'a> Chip8<'a> {
             sp: 0,
             dt: 0,
             st: 0,
+            sound_active: false,
+            audio_pattern: [0; AUDIO_PATTERN_SIZE],
+            audio_pitch: DEFAULT_AUDIO_PITCH,
             keyboard: [false; NUM_KEYS],
-            screen: [[false; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+            key_wait: None,
+            input_queue: VecDeque::new(),
+            release_frame: [None; NUM_KEYS],
+            frames: 0,
+            mid_frame: false,
+            input_observer: None,
+            quirks: Quirks::default(),
+            screen: [false; DISPLAY_WIDTH * DISPLAY_HEIGHT],
+            screen_dirty: true,
+            display_observer: None,
+            tracer: None,
+            profiler: None,
+            coverage: None,
+            rom_size: 0,
+            rng: StdRng::from_os_rng(),
             speaker,
         };
 
@@ -76,19 +185,108 @@ impl<'a> Chip8<'a> {
         }
 
         self.ram[PROGRAM_LOAD_ADDRESS..][..rom_length].copy_from_slice(&rom);
+        self.rom_size = rom_length;
         Ok(rom_length)
     }
 
-    pub fn key_down(&mut self, key_idx: usize) {
-        self.keyboard[key_idx] = true;
+    // The memory the ROM was loaded into, as the program left it
+    pub fn rom(&self) -> &[u8] {
+        &self.ram[PROGRAM_LOAD_ADDRESS..][..self.rom_size]
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    // Makes CXNN produce the same numbers on every run
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    pub fn key_down(&mut self, key: Key) {
+        // Only a transition counts as key press, so keys held since before
+        // FX0A started (or auto-repeat) don't finish it
+        let held = self.is_key_down(key);
+        if let Some(wait) = self.key_wait.as_mut()
+            && wait.pressed.is_none()
+            && !held
+        {
+            wait.pressed = Some(key);
+        }
+        self.keyboard[key.index()] = true;
+    }
+
+    pub fn key_up(&mut self, key: Key) {
+        if let Some(wait) = self.key_wait
+            && wait.pressed == Some(key)
+        {
+            self.v_registers[wait.register] = key.value();
+            self.key_wait = None;
+        }
+        self.keyboard[key.index()] = false;
+    }
+
+    // Queues a key event to be applied at the next instruction boundary. A
+    // release is held back until the key was down for at least one full
+    // frame, so taps shorter than a frame aren't lost between polls. Events
+    // for other keys aren't held back with it.
+    pub fn queue_key_event(&mut self, event: KeyEvent) {
+        let position = self
+            .input_queue
+            .iter()
+            .rposition(|queued| queued.timestamp <= event.timestamp)
+            .map_or(0, |i| i + 1);
+        self.input_queue.insert(position, event);
     }
 
-    pub fn key_up(&mut self, key_idx: usize) {
-        self.keyboard[key_idx] = false;
+    // Called with every queued key event when it gets applied, e.g. for
+    // recording input
+    pub fn set_input_observer(&mut self, observer: impl FnMut(KeyEvent) + 'a) {
+        self.input_observer = Some(Box::new(observer));
+    }
+
+    // True while FX0A blocks execution until a key is pressed and released
+    pub fn is_waiting_for_key(&self) -> bool {
+        self.key_wait.is_some()
+    }
+
+    // Executes one frame worth of instructions followed by a timer update.
+    // Stops executing early while waiting for a key, only the timers keep
+    // running then.
+    pub fn run_frame(&mut self, ticks: usize) {
+        for _ in 0..ticks {
+            self.exec();
+            if self.is_waiting_for_key() {
+                break;
+            }
+        }
+        self.update_timers();
     }
 
     pub fn exec(&mut self) {
+        self.apply_key_events();
+        self.mid_frame = true;
+        if self.is_waiting_for_key() {
+            return;
+        }
+
+        if self.tracer.is_some() {
+            let entry = self.trace_entry();
+            if let Some(tracer) = self.tracer.as_mut() {
+                tracer.trace(entry);
+            }
+        }
         let opcode = self.read_opcode(self.pc as usize);
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.record(self.pc, opcode);
+        }
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.record_execution(self.pc);
+        }
         let instruction = Instruction::from(opcode);
         self.advance_pc();
 
@@ -155,6 +353,7 @@ impl<'a> Chip8<'a> {
                 let x = self.v_registers[instruction.x()];
                 let y = self.v_registers[instruction.y()];
                 self.v_registers[instruction.x()] = x | y;
+                self.reset_vf_if_quirk();
             }
 
             // AND Vx, Vy: set Vx = V AND Vy
@@ -162,6 +361,7 @@ impl<'a> Chip8<'a> {
                 let x = self.v_registers[instruction.x()];
                 let y = self.v_registers[instruction.y()];
                 self.v_registers[instruction.x()] = x & y;
+                self.reset_vf_if_quirk();
             }
 
             // XOR Vx, Vy: set Vx = Vx XOR Vy
@@ -169,6 +369,7 @@ impl<'a> Chip8<'a> {
                 let x = self.v_registers[instruction.x()];
                 let y = self.v_registers[instruction.y()];
                 self.v_registers[instruction.x()] = x ^ y;
+                self.reset_vf_if_quirk();
             }
 
             // ADD Vx, Vy: set Vx = Vx + Vy, set VF = carry
@@ -192,7 +393,7 @@ impl<'a> Chip8<'a> {
 
             // SHR Vx {, Vy}: set Vx = Vx SHR 1o
             (0x08, _, _, 0x06) => {
-                let x = self.v_registers[instruction.x()];
+                let x = self.shift_operand(&instruction);
                 self.set_carry_if(x & 1 == 1);
                 self.v_registers[instruction.x()] = x >> 1;
             }
@@ -208,7 +409,7 @@ impl<'a> Chip8<'a> {
 
             // SHL Vx {, Vy}: set Vx = Vx SHL 1
             (0x08, _, _, 0x0E) => {
-                let x = self.v_registers[instruction.x()];
+                let x = self.shift_operand(&instruction);
                 let msb = (x & 0x80) >> 7; // Extract the MSB (most significant bit)
                 self.v_registers[0xF] = msb; // Set VF to the MSB
                 self.v_registers[instruction.x()] = x << 1; // Perform the left shift
@@ -228,11 +429,18 @@ impl<'a> Chip8<'a> {
             (0x0A, _, _, _) => self.i_register = instruction.nnn(),
 
             // JP V0, addr: jump to location nnn + V0
-            (0x0B, _, _, _) => self.pc = self.v_registers[0] as u16 + instruction.nnn(),
+            (0x0B, _, _, _) => {
+                let offset = if self.quirks.jump_uses_vx {
+                    self.v_registers[instruction.x()]
+                } else {
+                    self.v_registers[0]
+                };
+                self.pc = offset as u16 + instruction.nnn();
+            }
 
             // RND Vx, byte:  et Vx = random byte AND kk.
             (0x0C, _, _, _) => {
-                let n: u8 = rand::random();
+                let n: u8 = self.rng.random();
                 self.v_registers[instruction.x()] = n & instruction.nn();
             }
 
@@ -243,25 +451,26 @@ impl<'a> Chip8<'a> {
                 let y = self.v_registers[instruction.y()] as usize;
                 let start = self.i_register as usize;
                 let sprite: Vec<u8> = self.ram_read(start, instruction.n()).to_vec();
+                self.record_data_read(start, sprite.len());
 
                 let collision = self.draw_sprite(x, y, &sprite);
                 self.set_carry_if(collision);
             }
 
             // SKP Vx: skip next instruction if key with the value of Vx is
-            // pressed
+            // pressed. Only the low nibble of Vx is used.
             (0x0E, _, 0x09, 0x0E) => {
-                let x = self.v_registers[instruction.x()] as usize;
-                if self.is_key_down(x) {
+                let key = Key::from_low_nibble(self.v_registers[instruction.x()]);
+                if self.is_key_down(key) {
                     self.advance_pc();
                 }
             }
 
             // SKNP Vx: skip next instruction if key with the value of Vx is
-            // not pressed
+            // not pressed. Only the low nibble of Vx is used.
             (0x0E, _, 0x0A, 0x01) => {
-                let x = self.v_registers[instruction.x()] as usize;
-                if !self.is_key_down(x) {
+                let key = Key::from_low_nibble(self.v_registers[instruction.x()]);
+                if !self.is_key_down(key) {
                     self.advance_pc();
                 }
             }
@@ -270,6 +479,14 @@ impl<'a> Chip8<'a> {
             (0x0F, _, 0x00, 0x07) => self.v_registers[instruction.x()] = self.dt,
 
             // LD Vx, K: wait for a key press, store the value of the key in V
+            (0x0F, _, 0x00, 0x0A) if !self.quirks.key_wait_on_press => {
+                self.key_wait = Some(KeyWait {
+                    register: instruction.x(),
+                    pressed: None,
+                });
+            }
+
+            // LD Vx, K: old behaviour, finishes with any key already held
             (0x0F, _, 0x00, 0x0A) => {
                 let mut pressed = false;
                 for (i, key) in self.keyboard.iter().enumerate() {
@@ -307,6 +524,21 @@ impl<'a> Chip8<'a> {
                 self.i_register = x * 5;
             }
 
+            // AUDIO: load the 16 byte XO-CHIP audio pattern from memory
+            // location I. Reads past the end of RAM wrap around.
+            (0x0F, 0x00, 0x00, 0x02) => {
+                let start = self.i_register as usize;
+                self.audio_pattern = std::array::from_fn(|n| self.ram[(start + n) % RAM_SIZE]);
+                self.record_data_read(start, AUDIO_PATTERN_SIZE);
+                self.update_audio_pattern();
+            }
+
+            // PITCH Vx: set the XO-CHIP audio pattern playback rate
+            (0x0F, _, 0x03, 0x0A) => {
+                self.audio_pitch = self.v_registers[instruction.x()];
+                self.update_audio_pattern();
+            }
+
             // LD B, Vx: store BCD representation of Vx in memory locations I,
             // I+1, and I+2.
             (0x0F, _, 0x03, 0x03) => {
@@ -326,6 +558,7 @@ impl<'a> Chip8<'a> {
                 for n in 0..=instruction.x() {
                     self.ram[i + n] = self.v_registers[n];
                 }
+                self.increment_i_if_quirk(instruction.x());
             }
 
             // LD Vx, [I]: read registers V0 through Vx from memory starting at
@@ -336,26 +569,155 @@ impl<'a> Chip8<'a> {
                 for n in 0..=instruction.x() {
                     self.v_registers[n] = self.ram[i + n];
                 }
+                self.record_data_read(i, instruction.x() + 1);
+                self.increment_i_if_quirk(instruction.x());
             }
 
-            _ => eprintln!("Invalid instruction"),
+            _ => {
+                eprintln!("Invalid instruction: {:04X}", opcode);
+                if let Some(tracer) = self.tracer.as_mut() {
+                    tracer.dump();
+                }
+            }
         }
     }
 
     pub fn update_timers(&mut self) {
+        self.frames += 1;
+        self.mid_frame = false;
+
         if self.dt > 0 {
             self.dt -= 1;
         }
 
         let status = self.st > 0;
+        self.sound_active = status;
         self.speaker.beep(status);
         if status {
             self.st -= 1;
         }
     }
 
+    // Whether the speaker was told to beep during the last timer update
+    pub fn is_sound_active(&self) -> bool {
+        self.sound_active
+    }
+
     pub fn is_pixel_set(&self, x: usize, y: usize) -> bool {
-        self.screen[y][x]
+        self.screen[y * DISPLAY_WIDTH + x]
+    }
+
+    // Row-major pixel buffer, DISPLAY_WIDTH pixels per row
+    pub fn screen(&self) -> &[bool] {
+        &self.screen
+    }
+
+    // Returns whether any pixel changed since the last call
+    pub fn take_screen_dirty(&mut self) -> bool {
+        std::mem::replace(&mut self.screen_dirty, false)
+    }
+
+    pub fn save_screenshot(
+        &self,
+        path: &Path,
+        scale: usize,
+        palette: &Palette,
+    ) -> Result<(), String> {
+        screenshot::save_png(path, &self.screen, scale, palette)
+    }
+
+    pub fn set_display_observer(&mut self, observer: impl FnMut(DisplayEvent) + 'a) {
+        self.display_observer = Some(Box::new(observer));
+    }
+
+    pub fn clear_display_observer(&mut self) {
+        self.display_observer = None;
+    }
+
+    // Logs every executed instruction, see `Tracer` for the options
+    pub fn set_tracer(&mut self, tracer: Tracer<'a>) {
+        self.tracer = Some(tracer);
+    }
+
+    // Counts executed instructions, see `Profiler`
+    pub fn set_profiler(&mut self, profiler: Profiler) {
+        self.profiler = Some(profiler);
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    // Tracks which addresses get executed or read as data, see `Coverage`
+    pub fn set_coverage(&mut self, coverage: Coverage) {
+        self.coverage = Some(coverage);
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+    // endregion
+
+    // region: Debugger access
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u16) -> Result<(), String> {
+        if pc as usize > RAM_SIZE - INSTRUCTION_LENGTH as usize {
+            return Err(format!("PC out of range: {:#05x}", pc));
+        }
+        self.pc = pc;
+        Ok(())
+    }
+
+    pub fn i_register(&self) -> u16 {
+        self.i_register
+    }
+
+    pub fn set_i_register(&mut self, i: u16) {
+        self.i_register = i;
+    }
+
+    pub fn sp(&self) -> u8 {
+        self.sp
+    }
+
+    pub fn set_sp(&mut self, sp: u8) -> Result<(), String> {
+        if sp as usize > STACK_DEPTH {
+            return Err(format!("SP out of range: {}", sp));
+        }
+        self.sp = sp;
+        Ok(())
+    }
+
+    pub fn v_registers(&self) -> &[u8] {
+        &self.v_registers
+    }
+
+    pub fn v_registers_mut(&mut self) -> &mut [u8] {
+        &mut self.v_registers
+    }
+
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    // The state a trace line shows for the next instruction
+    pub fn trace_entry(&self) -> TraceEntry {
+        TraceEntry {
+            pc: self.pc,
+            opcode: self.read_opcode(self.pc as usize),
+            v_registers: self.v_registers,
+            i: self.i_register,
+            sp: self.sp,
+            dt: self.dt,
+            st: self.st,
+        }
     }
     // endregion
 
@@ -365,17 +727,48 @@ impl<'a> Chip8<'a> {
     }
 
     fn clear_screen(&mut self) {
-        self.screen = [[false; DISPLAY_WIDTH]; DISPLAY_HEIGHT];
+        if self.screen.contains(&true) {
+            self.screen = [false; DISPLAY_WIDTH * DISPLAY_HEIGHT];
+            self.screen_dirty = true;
+        }
+        self.notify_display_observer(DisplayEvent::Clear);
+    }
+
+    // The value 8XY6 and 8XYE shift into VX
+    fn shift_operand(&self, instruction: &Instruction) -> u8 {
+        if self.quirks.shift_uses_vy {
+            self.v_registers[instruction.y()]
+        } else {
+            self.v_registers[instruction.x()]
+        }
+    }
+
+    fn reset_vf_if_quirk(&mut self) {
+        if self.quirks.logic_resets_vf {
+            self.v_registers[0xF] = 0;
+        }
+    }
+
+    fn increment_i_if_quirk(&mut self, x: usize) {
+        if self.quirks.load_store_increments_i {
+            self.i_register += x as u16 + 1;
+        }
     }
 
     fn draw_sprite(&mut self, x: usize, y: usize, sprite: &[u8]) -> bool {
+        let x = x % DISPLAY_WIDTH;
+        let y = y % DISPLAY_HEIGHT;
         let mut pixel_collission = false;
 
         for (ly, b) in sprite.iter().enumerate() {
             for lx in 0..8 {
                 if b & 0b10000000 >> lx > 0 {
-                    let dx = (x + lx) % DISPLAY_WIDTH;
-                    let dy = (y + ly) % DISPLAY_HEIGHT;
+                    let (dx, dy) = (x + lx, y + ly);
+                    if self.quirks.clip_sprites && (dx >= DISPLAY_WIDTH || dy >= DISPLAY_HEIGHT) {
+                        continue;
+                    }
+                    let dx = dx % DISPLAY_WIDTH;
+                    let dy = dy % DISPLAY_HEIGHT;
                     pixel_collission = pixel_collission || self.is_pixel_set(dx, dy);
 
                     self.toggle_pixel(dx, dy);
@@ -383,11 +776,67 @@ impl<'a> Chip8<'a> {
             }
         }
 
+        self.notify_display_observer(DisplayEvent::Draw {
+            x,
+            y,
+            width: 8,
+            height: sprite.len(),
+            collision: pixel_collission,
+        });
         pixel_collission
     }
 
-    fn is_key_down(&self, key: usize) -> bool {
-        self.keyboard[key]
+    fn is_key_down(&self, key: Key) -> bool {
+        self.keyboard[key.index()]
+    }
+
+    fn apply_key_events(&mut self) {
+        let mut held = [false; NUM_KEYS];
+        let mut kept = VecDeque::new();
+        while let Some(event) = self.input_queue.pop_front() {
+            let index = event.key.index();
+            // A release that comes too early keeps itself and all later
+            // events for the same key for a following frame
+            held[index] = held[index]
+                || !event.pressed
+                    && self.release_frame[index].is_some_and(|frame| frame > self.frames);
+            if held[index] {
+                kept.push_back(event);
+                continue;
+            }
+
+            if event.pressed {
+                // Pressed after the frame started, the key only gets a full
+                // frame in the next one
+                let frames_down = if self.mid_frame { 2 } else { 1 };
+                self.release_frame[index] = Some(self.frames + frames_down);
+                self.key_down(event.key);
+            } else {
+                self.key_up(event.key);
+            }
+            if let Some(observer) = self.input_observer.as_mut() {
+                observer(event);
+            }
+        }
+        self.input_queue = kept;
+    }
+
+    fn update_audio_pattern(&mut self) {
+        let rate = audio_pattern_rate(self.audio_pitch);
+        self.speaker.set_pattern(&self.audio_pattern, rate);
+    }
+
+    fn notify_display_observer(&mut self, event: DisplayEvent) {
+        if let Some(observer) = self.display_observer.as_mut() {
+            observer(event);
+        }
+    }
+
+    // Memory read through I, as opposed to instruction fetches
+    fn record_data_read(&mut self, start: usize, len: usize) {
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.record_read(start, len);
+        }
     }
 
     fn ram_read(&self, start: usize, bytes: u8) -> &[u8] {
@@ -420,34 +869,35 @@ impl<'a> Chip8<'a> {
     }
 
     fn toggle_pixel(&mut self, x: usize, y: usize) {
-        self.screen[y][x] ^= true;
+        self.screen[y * DISPLAY_WIDTH + x] ^= true;
+        self.screen_dirty = true;
     }
     // endregion
 }
 
-struct Instruction {
-    opcode: u16,
-    nibbles: (u8, u8, u8, u8),
+pub(crate) struct Instruction {
+    pub(crate) opcode: u16,
+    pub(crate) nibbles: (u8, u8, u8, u8),
 }
 
 impl Instruction {
-    fn x(&self) -> usize {
+    pub(crate) fn x(&self) -> usize {
         self.nibbles.1 as usize
     }
 
-    fn y(&self) -> usize {
+    pub(crate) fn y(&self) -> usize {
         self.nibbles.2 as usize
     }
 
-    fn n(&self) -> u8 {
+    pub(crate) fn n(&self) -> u8 {
         self.nibbles.3
     }
 
-    fn nn(&self) -> u8 {
+    pub(crate) fn nn(&self) -> u8 {
         (self.opcode & 0xFF) as u8
     }
 
-    fn nnn(&self) -> u16 {
+    pub(crate) fn nnn(&self) -> u16 {
         self.opcode & 0xFFF
     }
 }
@@ -469,6 +919,8 @@ impl From<u16> for Instruction {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
 
     struct TestSpeaker {}
     impl TestSpeaker {
@@ -485,6 +937,96 @@ mod tests {
         Chip8::new(Box::new(TestSpeaker::new()))
     }
 
+    type Patterns = Rc<RefCell<Vec<([u8; AUDIO_PATTERN_SIZE], f32)>>>;
+
+    struct PatternSpeaker {
+        patterns: Patterns,
+    }
+
+    impl Speaker for PatternSpeaker {
+        fn beep(&mut self, _status: bool) {}
+
+        fn set_pattern(&mut self, pattern: &[u8; AUDIO_PATTERN_SIZE], rate: f32) {
+            self.patterns.borrow_mut().push((*pattern, rate));
+        }
+    }
+
+    #[test]
+    fn it_reports_the_sound_status_of_the_last_timer_update() {
+        let mut chip8 = new_chip8();
+        chip8.st = 1;
+        assert!(!chip8.is_sound_active());
+        chip8.update_timers();
+        assert!(chip8.is_sound_active());
+        chip8.update_timers();
+        assert!(!chip8.is_sound_active());
+    }
+
+    #[test]
+    fn it_generates_reproducible_random_numbers_from_a_seed() {
+        let random_numbers = || {
+            let mut chip8 = new_chip8();
+            chip8.set_seed(42);
+            // RND V0, 0xFF; JP 0x200
+            chip8.load_rom(vec![0xC0, 0xFF, 0x12, 0x00]).unwrap();
+            (0..8)
+                .map(|_| {
+                    chip8.exec();
+                    chip8.exec();
+                    chip8.v_registers[0]
+                })
+                .collect::<Vec<u8>>()
+        };
+        assert_eq!(random_numbers(), random_numbers());
+    }
+
+    #[test]
+    fn it_computes_xo_chip_pattern_rates() {
+        assert_eq!(audio_pattern_rate(64), 4000.0);
+        assert_eq!(audio_pattern_rate(112), 8000.0);
+        assert_eq!(audio_pattern_rate(16), 2000.0);
+    }
+
+    #[test]
+    fn it_passes_audio_patterns_to_the_speaker() {
+        let patterns = Patterns::default();
+        let mut chip8 = Chip8::new(Box::new(PatternSpeaker {
+            patterns: Rc::clone(&patterns),
+        }));
+
+        // LD I, 0x208; AUDIO; LD V1, 112; PITCH V1; pattern data
+        let mut rom = vec![0xA2, 0x08, 0xF0, 0x02, 0x61, 0x70, 0xF1, 0x3A];
+        rom.extend(0..AUDIO_PATTERN_SIZE as u8);
+        chip8.load_rom(rom).unwrap();
+        for _ in 0..4 {
+            chip8.exec();
+        }
+
+        let expected: [u8; AUDIO_PATTERN_SIZE] = std::array::from_fn(|i| i as u8);
+        assert_eq!(
+            *patterns.borrow(),
+            vec![(expected, 4000.0), (expected, 8000.0)]
+        );
+    }
+
+    #[test]
+    fn it_wraps_audio_patterns_around_the_end_of_ram() {
+        let patterns = Patterns::default();
+        let mut chip8 = Chip8::new(Box::new(PatternSpeaker {
+            patterns: Rc::clone(&patterns),
+        }));
+
+        // AUDIO with I 8 bytes before the end of RAM
+        chip8.load_rom(vec![0xF0, 0x02]).unwrap();
+        chip8.i_register = (RAM_SIZE - 8) as u16;
+        chip8.ram[RAM_SIZE - 8..].fill(0xAA);
+        chip8.exec();
+
+        let mut expected = [0xAA; AUDIO_PATTERN_SIZE];
+        expected[8..].copy_from_slice(&chip8.ram[..8]);
+        assert_eq!(patterns.borrow()[0].0, expected);
+    }
+
     #[test]
     fn toggle_pixel_can_toggle_a_pixel() {
         let mut chip8 = new_chip8();
@@ -495,6 +1037,64 @@ mod tests {
         assert!(!chip8.is_pixel_set(5, 5));
     }
 
+    #[test]
+    fn screen_is_a_row_major_buffer() {
+        let mut chip8 = new_chip8();
+        chip8.toggle_pixel(3, 2);
+        assert_eq!(chip8.screen().len(), DISPLAY_WIDTH * DISPLAY_HEIGHT);
+        assert!(chip8.screen()[2 * DISPLAY_WIDTH + 3]);
+    }
+
+    #[test]
+    fn it_tracks_whether_the_screen_changed() {
+        let mut chip8 = new_chip8();
+        assert!(chip8.take_screen_dirty());
+        assert!(!chip8.take_screen_dirty());
+        chip8.draw_sprite(0, 0, &[0x80]);
+        assert!(chip8.take_screen_dirty());
+        chip8.clear_screen();
+        assert!(chip8.take_screen_dirty());
+    }
+
+    #[test]
+    fn it_only_marks_the_screen_dirty_on_actual_changes() {
+        let mut chip8 = new_chip8();
+        chip8.take_screen_dirty();
+        chip8.clear_screen();
+        chip8.draw_sprite(0, 0, &[0x00, 0x00]);
+        assert!(!chip8.take_screen_dirty());
+    }
+
+    #[test]
+    fn it_notifies_the_display_observer() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut chip8 = new_chip8();
+        let recorded = Rc::clone(&events);
+        chip8.set_display_observer(move |event| recorded.borrow_mut().push(event));
+
+        // LD V0, 0x42; DRW V0, V0, 2; CLS
+        chip8
+            .load_rom(vec![0x60, 0x42, 0xD0, 0x02, 0x00, 0xE0])
+            .unwrap();
+        chip8.exec();
+        chip8.exec();
+        chip8.exec();
+
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                DisplayEvent::Draw {
+                    x: 2,
+                    y: 2,
+                    width: 8,
+                    height: 2,
+                    collision: false
+                },
+                DisplayEvent::Clear
+            ]
+        );
+    }
+
     #[test]
     fn draw_sprite_returns_true_when_overwriting() {
         let mut chip8 = new_chip8();
@@ -505,11 +1105,308 @@ mod tests {
     #[test]
     fn it_can_press_and_release_keys() {
         let mut chip8 = new_chip8();
-        assert!(!chip8.is_key_down(1));
-        chip8.key_down(1);
-        assert!(chip8.is_key_down(1));
-        chip8.key_up(1);
-        assert!(!chip8.is_key_down(1));
+        assert!(!chip8.is_key_down(key(1)));
+        chip8.key_down(key(1));
+        assert!(chip8.is_key_down(key(1)));
+        chip8.key_up(key(1));
+        assert!(!chip8.is_key_down(key(1)));
+    }
+
+    #[test]
+    fn skip_if_key_uses_the_low_nibble_of_out_of_range_values() {
+        let mut chip8 = new_chip8();
+        // LD V0, 0x1A; SKP V0; SKP V0; LD V0, 0xFF; SKNP V0
+        chip8
+            .load_rom(vec![
+                0x60, 0x1A, 0xE0, 0x9E, 0xE0, 0x9E, 0x60, 0xFF, 0xE0, 0xA1,
+            ])
+            .unwrap();
+        chip8.exec();
+        chip8.exec();
+        assert_eq!(chip8.pc, 0x204);
+
+        chip8.key_down(key(0xA));
+        chip8.exec();
+        assert_eq!(chip8.pc, 0x208);
+
+        chip8.pc = 0x206;
+        chip8.exec();
+        chip8.exec();
+        assert_eq!(chip8.pc, 0x20C);
+    }
+
+    #[test]
+    fn it_dumps_the_trace_ring_buffer_on_invalid_instructions() {
+        let mut output = Vec::new();
+        {
+            let mut chip8 = new_chip8();
+            chip8.set_tracer(Tracer::new(&mut output, None, Some(2)));
+            // LD V0, 0x12; LD V1, 0x34; LD I, 0x300; invalid
+            chip8
+                .load_rom(vec![0x60, 0x12, 0x61, 0x34, 0xA3, 0x00, 0xFF, 0xFF])
+                .unwrap();
+            for _ in 0..4 {
+                chip8.exec();
+            }
+        }
+
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("0204: A300  LD I, 0x300"));
+        assert!(lines[1].starts_with("0206: FFFF  DW 0xffff"));
+        assert!(lines[1].contains("V=1234"));
+        assert!(lines[1].contains("I=0300"));
+    }
+
+    #[test]
+    fn it_feeds_executed_instructions_to_the_profiler() {
+        let mut chip8 = new_chip8();
+        chip8.set_profiler(Profiler::new());
+        // LD V0, 1; JP 0x202
+        chip8.load_rom(vec![0x60, 0x01, 0x12, 0x02]).unwrap();
+        chip8.run_frame(TICKS_PER_FRAME);
+        assert_eq!(
+            chip8.profiler().unwrap().instructions(),
+            TICKS_PER_FRAME as u64
+        );
+    }
+
+    #[test]
+    fn it_records_coverage_of_code_and_sprite_data() {
+        let mut chip8 = new_chip8();
+        chip8.set_coverage(Coverage::new());
+        // LD I, 0x206; DRW V0, V0, 2; JP 0x204; sprite data
+        chip8
+            .load_rom(vec![0xA2, 0x06, 0xD0, 0x02, 0x12, 0x04, 0xFF, 0x81])
+            .unwrap();
+        chip8.run_frame(TICKS_PER_FRAME);
+
+        let summary = chip8.coverage().unwrap().summary(chip8.rom().len());
+        assert_eq!(summary.executed, 6);
+        assert_eq!(summary.read, 2);
+        assert_eq!(summary.untouched, 0);
+    }
+
+    fn chip8_waiting_for_key() -> Chip8<'static> {
+        let mut chip8 = new_chip8();
+        // LD V3, K; LD V0, 0x42
+        chip8.load_rom(vec![0xF3, 0x0A, 0x60, 0x42]).unwrap();
+        chip8.exec();
+        chip8
+    }
+
+    #[test]
+    fn key_wait_finishes_when_a_key_is_pressed_and_released() {
+        let mut chip8 = chip8_waiting_for_key();
+        assert!(chip8.is_waiting_for_key());
+
+        chip8.key_down(key(7));
+        chip8.exec();
+        assert!(chip8.is_waiting_for_key());
+        assert_eq!(chip8.v_registers[0], 0);
+
+        chip8.key_up(key(7));
+        assert!(!chip8.is_waiting_for_key());
+        assert_eq!(chip8.v_registers[3], 7);
+        chip8.exec();
+        assert_eq!(chip8.v_registers[0], 0x42);
+    }
+
+    #[test]
+    fn key_wait_ignores_keys_held_before_it_started() {
+        let mut chip8 = new_chip8();
+        chip8.load_rom(vec![0xF3, 0x0A]).unwrap();
+        chip8.key_down(key(2));
+        chip8.exec();
+
+        chip8.key_down(key(2));
+        chip8.key_up(key(2));
+        assert!(chip8.is_waiting_for_key());
+
+        chip8.key_down(key(2));
+        chip8.key_up(key(2));
+        assert!(!chip8.is_waiting_for_key());
+        assert_eq!(chip8.v_registers[3], 2);
+    }
+
+    #[test]
+    fn key_wait_can_finish_on_press_as_a_quirk() {
+        let mut chip8 = new_chip8();
+        chip8.set_quirks(Quirks {
+            key_wait_on_press: true,
+            ..Quirks::default()
+        });
+        chip8.load_rom(vec![0xF3, 0x0A]).unwrap();
+
+        chip8.exec();
+        assert_eq!(chip8.pc, PROGRAM_LOAD_ADDRESS as u16);
+        chip8.key_down(key(5));
+        chip8.exec();
+        assert_eq!(chip8.v_registers[3], 5);
+        assert_eq!(chip8.pc, PROGRAM_LOAD_ADDRESS as u16 + 2);
+    }
+
+    #[test]
+    fn it_emulates_vip_quirks() {
+        let mut chip8 = new_chip8();
+        chip8.set_quirks(Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            logic_resets_vf: true,
+            ..Quirks::default()
+        });
+        // LD V1, 0x06; SHR V0, V1; LD VF, 0x01; OR V0, V1; LD I, 0x300;
+        // LD [I], V1
+        chip8
+            .load_rom(vec![
+                0x61, 0x06, 0x80, 0x16, 0x6F, 0x01, 0x80, 0x11, 0xA3, 0x00, 0xF1, 0x55,
+            ])
+            .unwrap();
+        chip8.exec();
+        chip8.exec();
+        assert_eq!(chip8.v_registers[0], 0x03);
+        chip8.exec();
+        chip8.exec();
+        assert_eq!(chip8.v_registers[0xF], 0);
+        chip8.exec();
+        chip8.exec();
+        assert_eq!(chip8.i_register, 0x302);
+    }
+
+    #[test]
+    fn it_emulates_chip48_jumps_and_clipping() {
+        let mut chip8 = new_chip8();
+        chip8.set_quirks(Quirks {
+            jump_uses_vx: true,
+            clip_sprites: true,
+            ..Quirks::default()
+        });
+        chip8.v_registers[2] = 0x10;
+        // JP V2, 0x280
+        chip8.load_rom(vec![0xB2, 0x80]).unwrap();
+        chip8.exec();
+        assert_eq!(chip8.pc, 0x290);
+
+        chip8.draw_sprite(DISPLAY_WIDTH - 4, 0, &[0xff]);
+        assert!(!chip8.is_pixel_set(0, 0));
+        assert!(chip8.is_pixel_set(DISPLAY_WIDTH - 1, 0));
+    }
+
+    #[test]
+    fn run_frame_only_runs_timers_while_waiting_for_a_key() {
+        let mut chip8 = chip8_waiting_for_key();
+        chip8.dt = 5;
+        chip8.run_frame(TICKS_PER_FRAME);
+        assert_eq!(chip8.dt, 4);
+        assert_eq!(chip8.pc, PROGRAM_LOAD_ADDRESS as u16 + 2);
+    }
+
+    fn key(value: u8) -> Key {
+        Key::new(value).unwrap()
+    }
+
+    fn key_event(value: u8, pressed: bool, timestamp: u64) -> KeyEvent {
+        KeyEvent {
+            key: key(value),
+            pressed,
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn queued_key_taps_last_at_least_one_frame() {
+        let mut chip8 = new_chip8();
+        // JP 0x200
+        chip8.load_rom(vec![0x12, 0x00]).unwrap();
+        chip8.queue_key_event(key_event(4, true, 10));
+        chip8.queue_key_event(key_event(4, false, 12));
+
+        chip8.exec();
+        assert!(chip8.is_key_down(key(4)));
+        chip8.run_frame(TICKS_PER_FRAME);
+        assert!(chip8.is_key_down(key(4)));
+
+        chip8.exec();
+        assert!(!chip8.is_key_down(key(4)));
+    }
+
+    #[test]
+    fn queued_key_taps_in_the_middle_of_a_frame_last_a_full_frame() {
+        let mut chip8 = new_chip8();
+        chip8.load_rom(vec![0x12, 0x00]).unwrap();
+        chip8.exec();
+        chip8.queue_key_event(key_event(4, true, 10));
+        chip8.queue_key_event(key_event(4, false, 12));
+
+        chip8.exec();
+        assert!(chip8.is_key_down(key(4)));
+        chip8.update_timers();
+        chip8.run_frame(TICKS_PER_FRAME);
+        assert!(chip8.is_key_down(key(4)));
+
+        chip8.exec();
+        assert!(!chip8.is_key_down(key(4)));
+    }
+
+    #[test]
+    fn held_back_releases_dont_hold_back_other_keys() {
+        let mut chip8 = new_chip8();
+        chip8.load_rom(vec![0x12, 0x00]).unwrap();
+        chip8.queue_key_event(key_event(4, true, 10));
+        chip8.queue_key_event(key_event(4, false, 12));
+        chip8.queue_key_event(key_event(4, true, 13));
+        chip8.queue_key_event(key_event(7, true, 14));
+        chip8.exec();
+        assert!(chip8.is_key_down(key(4)));
+        assert!(chip8.is_key_down(key(7)));
+
+        chip8.queue_key_event(key_event(7, false, 15));
+        chip8.update_timers();
+        chip8.exec();
+        assert!(!chip8.is_key_down(key(7)));
+        // Released and pressed again in order
+        assert!(chip8.is_key_down(key(4)));
+        assert!(chip8.input_queue.is_empty());
+    }
+
+    #[test]
+    fn queued_key_events_are_applied_in_timestamp_order() {
+        let applied = Rc::new(RefCell::new(Vec::new()));
+        let mut chip8 = new_chip8();
+        let recorded = Rc::clone(&applied);
+        chip8.set_input_observer(move |event| recorded.borrow_mut().push(event));
+        chip8.load_rom(vec![0x12, 0x00]).unwrap();
+
+        chip8.queue_key_event(key_event(1, true, 20));
+        chip8.queue_key_event(key_event(2, true, 10));
+        chip8.queue_key_event(key_event(3, true, 20));
+        chip8.exec();
+
+        assert_eq!(
+            *applied.borrow(),
+            vec![
+                key_event(2, true, 10),
+                key_event(1, true, 20),
+                key_event(3, true, 20)
+            ]
+        );
+    }
+
+    #[test]
+    fn queued_key_events_finish_a_key_wait() {
+        let mut chip8 = chip8_waiting_for_key();
+        chip8.queue_key_event(key_event(9, true, 0));
+        chip8.queue_key_event(key_event(9, false, 1));
+        // Pressed in the middle of the first frame, the key stays down for
+        // all of the second
+        chip8.run_frame(TICKS_PER_FRAME);
+        chip8.run_frame(TICKS_PER_FRAME);
+        assert!(chip8.is_waiting_for_key());
+
+        chip8.run_frame(TICKS_PER_FRAME);
+        assert!(!chip8.is_waiting_for_key());
+        assert_eq!(chip8.v_registers[3], 9);
     }
 
     #[test]
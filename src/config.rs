@@ -0,0 +1,402 @@
+// User settings in config.toml under the XDG config directory
+// (~/.config/chip-8 unless $XDG_CONFIG_HOME says otherwise), read when
+// running a ROM in a window. The top-level settings are defaults: the ROM
+// database's settings for a ROM take precedence. A [roms."<sha1>"] table
+// overrides both for one ROM, and command line options override everything.
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use toml_edit::{Array, DocumentMut, value};
+
+use chip_8::palette::Palette;
+use chip_8::romdb;
+use chip_8::synth::{ToneSettings, Waveform};
+
+use crate::Keymap;
+
+const RECENT_ROMS: usize = 10;
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    #[serde(deserialize_with = "range::scale")]
+    pub scale: Option<u32>,
+    #[serde(with = "text")]
+    pub palette: Option<Palette>,
+    // Instructions per frame
+    #[serde(deserialize_with = "range::speed")]
+    pub speed: Option<usize>,
+    #[serde(deserialize_with = "range::volume")]
+    pub volume: Option<f32>,
+    #[serde(deserialize_with = "range::pitch")]
+    pub pitch: Option<f32>,
+    #[serde(with = "text")]
+    pub waveform: Option<Waveform>,
+    #[serde(with = "text")]
+    pub keymap: Option<Keymap>,
+    // Platform whose quirks are used for ROMs the ROM database doesn't know
+    pub platform: Option<String>,
+    // Most recently run first
+    pub recent: Vec<PathBuf>,
+    // Settings for single ROMs by the SHA-1 of their contents
+    pub roms: BTreeMap<String, RomConfig>,
+}
+
+// The settings of a [roms."<sha1>"] table, which count as if they were given
+// on the command line
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RomConfig {
+    #[serde(deserialize_with = "range::scale")]
+    pub scale: Option<u32>,
+    #[serde(with = "text")]
+    pub palette: Option<Palette>,
+    #[serde(deserialize_with = "range::speed")]
+    pub speed: Option<usize>,
+    #[serde(deserialize_with = "range::volume")]
+    pub volume: Option<f32>,
+    #[serde(deserialize_with = "range::pitch")]
+    pub pitch: Option<f32>,
+    #[serde(with = "text")]
+    pub waveform: Option<Waveform>,
+    #[serde(with = "text")]
+    pub keymap: Option<Keymap>,
+    // Platform whose quirks are used even if the ROM database knows the ROM
+    pub platform: Option<String>,
+}
+
+// Where the config file lives, if there is a home directory to put it in
+pub fn path() -> Option<PathBuf> {
+    config_path(env::var_os("XDG_CONFIG_HOME"), dirs::home_dir())
+}
+
+// The XDG base directory spec ignores relative paths in $XDG_CONFIG_HOME
+fn config_path(config_home: Option<OsString>, home: Option<PathBuf>) -> Option<PathBuf> {
+    let dir = config_home
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| Some(home?.join(".config")))?;
+    Some(dir.join("chip-8").join("config.toml"))
+}
+
+impl Config {
+    // A missing file is an empty config
+    pub fn load(path: &Path) -> Result<Config, String> {
+        match fs::read_to_string(path) {
+            Ok(text) => Config::parse(&text)
+                .map_err(|e| format!("Invalid config file {}: {}", path.display(), e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(format!("Cannot read config file {}: {}", path.display(), e)),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Config, String> {
+        toml::from_str(text).map_err(|e| e.message().to_string())
+    }
+
+    // Writes the settings the emulator changes, the tone and the recent ROMs,
+    // into the file as it is now. Everything else in it, comments included,
+    // is left alone.
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let error = |e: &dyn Display| format!("Cannot write config file {}: {}", path.display(), e);
+        let mut document = match fs::read_to_string(path) {
+            Ok(text) => text.parse::<DocumentMut>().map_err(|e| error(&e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => DocumentMut::new(),
+            Err(e) => return Err(error(&e)),
+        };
+        if let Some(pitch) = self.pitch {
+            document["pitch"] = value(float(pitch));
+        }
+        if let Some(volume) = self.volume {
+            document["volume"] = value(float(volume));
+        }
+        if let Some(waveform) = self.waveform {
+            document["waveform"] = value(waveform.to_string());
+        }
+        let recent: Array = self
+            .recent
+            .iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect();
+        document["recent"] = value(recent);
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| error(&e))?;
+        }
+        fs::write(path, document.to_string()).map_err(|e| error(&e))
+    }
+
+    // The table for the ROM with the given contents, if there is one
+    pub fn rom(&self, rom: &[u8]) -> Option<&RomConfig> {
+        let sha1 = romdb::sha1_hex(rom);
+        self.roms
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(&sha1))
+            .map(|(_, config)| config)
+    }
+
+    pub fn tone(&self) -> ToneSettings {
+        let default = ToneSettings::default();
+        ToneSettings {
+            pitch: self.pitch.unwrap_or(default.pitch),
+            volume: self.volume.unwrap_or(default.volume),
+            waveform: self.waveform.unwrap_or(default.waveform),
+            ..default
+        }
+    }
+
+    // Remembers what the hotkeys changed between the tone a run started with
+    // and the one it ended with. What they left alone may have come from the
+    // command line or the ROM's table and stays out of the defaults. Muting
+    // isn't remembered, so the next ROM doesn't start silent by surprise.
+    pub fn remember_tone_changes(&mut self, start: ToneSettings, end: ToneSettings) {
+        if end.pitch != start.pitch {
+            self.pitch = Some(end.pitch);
+        }
+        if end.volume != start.volume {
+            self.volume = Some(end.volume);
+        }
+        if end.waveform != start.waveform {
+            self.waveform = Some(end.waveform);
+        }
+    }
+
+    pub fn add_recent(&mut self, rom_path: &Path) {
+        let rom_path = rom_path
+            .canonicalize()
+            .unwrap_or_else(|_| rom_path.to_path_buf());
+        self.recent.retain(|path| *path != rom_path);
+        self.recent.insert(0, rom_path);
+        self.recent.truncate(RECENT_ROMS);
+    }
+}
+
+// Widening an f32 as is would write 0.1 as 0.10000000149011612
+fn float(number: f32) -> f64 {
+    number.to_string().parse().unwrap_or(number.into())
+}
+
+// Settings stored as the text the command line takes
+mod text {
+    use std::str::FromStr;
+
+    use serde::{Deserialize, Deserializer, de};
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        T: FromStr<Err = String>,
+        D: Deserializer<'de>,
+    {
+        Option::<String>::deserialize(deserializer)?
+            .map(|text| text.parse().map_err(de::Error::custom))
+            .transpose()
+    }
+}
+
+// Numbers limited to what the command line accepts
+mod range {
+    use std::fmt::Display;
+    use std::ops::RangeInclusive;
+
+    use chip_8::synth::{MAX_PITCH, MIN_PITCH};
+    use serde::{Deserialize, Deserializer, de};
+
+    pub fn scale<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+        checked(deserializer, "scale", 1..=64, "1-64")
+    }
+
+    pub fn speed<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<usize>, D::Error> {
+        checked(deserializer, "speed", 1..=usize::MAX, "at least 1")
+    }
+
+    pub fn volume<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f32>, D::Error> {
+        checked(deserializer, "volume", 0.0..=1.0, "0-1")
+    }
+
+    pub fn pitch<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f32>, D::Error> {
+        checked(deserializer, "pitch", MIN_PITCH..=MAX_PITCH, "20-20000 Hz")
+    }
+
+    fn checked<'de, T, D>(
+        deserializer: D,
+        name: &str,
+        range: RangeInclusive<T>,
+        expected: &str,
+    ) -> Result<Option<T>, D::Error>
+    where
+        T: Deserialize<'de> + Display + PartialOrd,
+        D: Deserializer<'de>,
+    {
+        match Option::<T>::deserialize(deserializer)? {
+            Some(number) if !range.contains(&number) => Err(de::Error::custom(format!(
+                "{} {} is out of range (expected {})",
+                name, number, expected
+            ))),
+            number => Ok(number),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_reads_settings() {
+        let config = Config::parse(
+            r##"
+                scale = 8
+                palette = "#102030,f0e0d0"
+                speed = 15
+                volume = 0.5
+                waveform = "triangle"
+                keymap = "0,1,2,3,4,5,6,7,8,9,A,B,C,D,E,F"
+                platform = "chip48"
+                recent = ["/roms/a.ch8"]
+            "##,
+        )
+        .unwrap();
+        assert_eq!(config.scale, Some(8));
+        assert_eq!(
+            config.palette,
+            Some(Palette::new([0x10, 0x20, 0x30], [0xf0, 0xe0, 0xd0]))
+        );
+        assert_eq!(config.speed, Some(15));
+        assert_eq!(config.tone().volume, 0.5);
+        assert_eq!(config.tone().waveform, Waveform::Triangle);
+        assert_eq!(config.tone().pitch, ToneSettings::default().pitch);
+        assert_eq!(
+            config.keymap,
+            "0,1,2,3,4,5,6,7,8,9,A,B,C,D,E,F".parse().ok()
+        );
+        assert_eq!(config.platform.as_deref(), Some("chip48"));
+        assert_eq!(config.recent, [PathBuf::from("/roms/a.ch8")]);
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn it_reads_settings_for_single_roms() {
+        let rom = [0x12, 0x00];
+        let config = Config::parse(&format!(
+            r#"
+                speed = 10
+                [roms."{}"]
+                speed = 20
+                platform = "superchip"
+            "#,
+            romdb::sha1_hex(&rom).to_uppercase()
+        ))
+        .unwrap();
+        let rom_config = config.rom(&rom).unwrap();
+        assert_eq!(rom_config.speed, Some(20));
+        assert_eq!(rom_config.platform.as_deref(), Some("superchip"));
+        assert!(config.rom(&[0x12, 0x02]).is_none());
+    }
+
+    #[test]
+    fn it_updates_the_file_in_place() {
+        let path = env::temp_dir().join(format!("chip-8-config-{}.toml", std::process::id()));
+        let text =
+            "# Tuned by hand\nscale = 4 # big enough\nvolume = 0.5\n\n[roms.abc]\nspeed = 30\n";
+        fs::write(&path, text).unwrap();
+
+        let mut config = Config::load(&path).unwrap();
+        let start = config.tone();
+        let end = ToneSettings {
+            volume: 0.1,
+            ..start
+        };
+        config.remember_tone_changes(start, end);
+        config.add_recent(Path::new("missing/a.ch8"));
+        config.save(&path).unwrap();
+        let saved = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(saved.starts_with("# Tuned by hand\nscale = 4 # big enough\nvolume = 0.1\n"));
+        assert!(saved.contains("[roms.abc]\nspeed = 30\n"));
+        let reloaded = Config::parse(&saved).unwrap();
+        assert_eq!(reloaded.volume, Some(0.1));
+        assert_eq!(reloaded.recent, config.recent);
+        assert_eq!(reloaded.roms["abc"].speed, Some(30));
+    }
+
+    #[test]
+    fn it_rejects_invalid_settings() {
+        assert_eq!(
+            Config::parse("palette = \"000000\"").unwrap_err(),
+            "expected two colors separated by a comma"
+        );
+        assert!(Config::parse("keymap = \"X,1,2\"").is_err());
+        assert!(Config::parse("scale = \"big\"").is_err());
+        assert_eq!(
+            Config::parse("scale = 0").unwrap_err(),
+            "scale 0 is out of range (expected 1-64)"
+        );
+        assert!(Config::parse("speed = 0").is_err());
+        assert!(Config::parse("volume = 1.5").is_err());
+        assert!(Config::parse("pitch = 0.0").is_err());
+        assert!(Config::parse("[roms.abc]\npitch = -440.0").is_err());
+        assert!(Config::parse("sacle = 8").is_err());
+        assert!(Config::parse("[roms.abc]\nrecent = []").is_err());
+    }
+
+    #[test]
+    fn it_finds_the_xdg_config_directory() {
+        let home = Some(PathBuf::from("/home/chip"));
+        assert_eq!(
+            config_path(None, home.clone()),
+            Some(PathBuf::from("/home/chip/.config/chip-8/config.toml"))
+        );
+        assert_eq!(
+            config_path(Some("/etc/xdg".into()), home.clone()),
+            Some(PathBuf::from("/etc/xdg/chip-8/config.toml"))
+        );
+        assert_eq!(
+            config_path(Some("relative".into()), home),
+            config_path(None, Some(PathBuf::from("/home/chip")))
+        );
+        assert_eq!(config_path(None, None), None);
+    }
+
+    #[test]
+    fn it_remembers_tone_changes() {
+        let mut config = Config::default();
+        let start = ToneSettings::default();
+        let end = ToneSettings {
+            volume: 0.75,
+            muted: true,
+            ..start
+        };
+        config.remember_tone_changes(start, end);
+        assert_eq!(config.volume, Some(0.75));
+        assert_eq!(config.pitch, None);
+        assert_eq!(config.waveform, None);
+        assert!(!config.tone().muted);
+    }
+
+    #[test]
+    fn it_keeps_the_most_recent_roms_first() {
+        let mut config = Config::default();
+        for i in 0..12 {
+            config.add_recent(Path::new(&format!("missing/{}.ch8", i)));
+        }
+        config.add_recent(Path::new("missing/5.ch8"));
+        assert_eq!(config.recent.len(), RECENT_ROMS);
+        assert_eq!(config.recent[0], Path::new("missing/5.ch8"));
+        assert_eq!(config.recent[1], Path::new("missing/11.ch8"));
+        assert_eq!(
+            config
+                .recent
+                .iter()
+                .filter(|p| p.ends_with("5.ch8"))
+                .count(),
+            1
+        );
+    }
+}
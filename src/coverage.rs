@@ -0,0 +1,256 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::Range;
+
+use crate::chip8::{PROGRAM_LOAD_ADDRESS, RAM_SIZE};
+use crate::disasm;
+
+const EXECUTED: u8 = 0b01;
+const READ: u8 = 0b10;
+
+// HTML coverage map layout
+const BYTES_PER_ROW: usize = 16;
+
+// Which memory addresses were executed as instructions and which were read as
+// sprites or other data through I
+#[derive(Clone, Debug)]
+pub struct Coverage {
+    marks: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub size: usize,
+    pub executed: usize,
+    pub read: usize,
+    pub untouched: usize,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage {
+            marks: vec![0; RAM_SIZE],
+        }
+    }
+
+    pub fn record_execution(&mut self, pc: u16) {
+        let pc = pc as usize;
+        self.mark(pc..pc + 2, EXECUTED);
+    }
+
+    pub fn record_read(&mut self, start: usize, len: usize) {
+        self.mark(start..start + len, READ);
+    }
+
+    pub fn is_executed(&self, address: usize) -> bool {
+        self.marks.get(address).is_some_and(|m| m & EXECUTED != 0)
+    }
+
+    pub fn is_read(&self, address: usize) -> bool {
+        self.marks.get(address).is_some_and(|m| m & READ != 0)
+    }
+
+    // Counts for the bytes of a ROM of the given size. Bytes that were both
+    // executed and read count for both.
+    pub fn summary(&self, rom_size: usize) -> Summary {
+        let rom = &self.marks[PROGRAM_LOAD_ADDRESS..][..rom_size];
+        Summary {
+            size: rom_size,
+            executed: rom.iter().filter(|&m| m & EXECUTED != 0).count(),
+            read: rom.iter().filter(|&m| m & READ != 0).count(),
+            untouched: rom.iter().filter(|&&m| m == 0).count(),
+        }
+    }
+
+    // Annotated disassembly of the ROM. Each line has the address, the raw
+    // bytes, a marker (X executed, R read as data, - never touched) and the
+    // instruction, or the bits of bytes that were read as sprite data.
+    pub fn write_text(&self, mut writer: impl Write, rom: &[u8]) -> io::Result<()> {
+        writeln!(writer, "{}", self.summary(rom.len()))?;
+        writeln!(writer)?;
+
+        let mut offset = 0;
+        while offset < rom.len() {
+            let address = PROGRAM_LOAD_ADDRESS + offset;
+            let word = rom
+                .get(offset..offset + 2)
+                .map(|bytes| (bytes[0] as u16) << 8 | bytes[1] as u16);
+            let untouched_pair = !self.is_touched(address) && !self.is_touched(address + 1);
+            match word {
+                Some(opcode) if self.is_executed(address) || untouched_pair => {
+                    writeln!(
+                        writer,
+                        "{:#05x}  {:04X}  {:<2}  {}",
+                        address,
+                        opcode,
+                        self.marker(address),
+                        disasm::disassemble(opcode)
+                    )?;
+                    offset += 2;
+                }
+                _ => {
+                    let byte = rom[offset];
+                    let bits: String = (0..8)
+                        .map(|bit| if byte & 0x80 >> bit != 0 { '#' } else { '.' })
+                        .collect();
+                    writeln!(
+                        writer,
+                        "{:#05x}  {:02X}    {:<2}  {}",
+                        address,
+                        byte,
+                        self.marker(address),
+                        bits
+                    )?;
+                    offset += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // A standalone HTML page with the summary and a map of the ROM, one cell
+    // per byte colored by how it was used
+    pub fn write_html(&self, mut writer: impl Write, rom: &[u8]) -> io::Result<()> {
+        writeln!(
+            writer,
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <title>CHIP-8 coverage</title>\n<style>\n\
+             body {{ font-family: monospace; }}\n\
+             td {{ padding: 2px 4px; }}\n\
+             .x {{ background: #8c8; }}\n\
+             .r {{ background: #8af; }}\n\
+             .xr {{ background: #fc6; }}\n\
+             .u {{ background: #eee; color: #999; }}\n\
+             </style>\n</head>\n<body>"
+        )?;
+        writeln!(writer, "<p>{}</p>", self.summary(rom.len()))?;
+        writeln!(
+            writer,
+            "<p><span class=\"x\">executed</span> <span class=\"r\">read as data</span> \
+             <span class=\"xr\">both</span> <span class=\"u\">never touched</span></p>"
+        )?;
+
+        writeln!(writer, "<table>")?;
+        for (row, bytes) in rom.chunks(BYTES_PER_ROW).enumerate() {
+            let row_address = PROGRAM_LOAD_ADDRESS + row * BYTES_PER_ROW;
+            write!(writer, "<tr><th>{:#05x}</th>", row_address)?;
+            for (column, byte) in bytes.iter().enumerate() {
+                let address = row_address + column;
+                let class = match (self.is_executed(address), self.is_read(address)) {
+                    (true, true) => "xr",
+                    (true, false) => "x",
+                    (false, true) => "r",
+                    (false, false) => "u",
+                };
+                write!(
+                    writer,
+                    "<td class=\"{}\" title=\"{:#05x}\">{:02X}</td>",
+                    class, address, byte
+                )?;
+            }
+            writeln!(writer, "</tr>")?;
+        }
+        writeln!(writer, "</table>\n</body>\n</html>")
+    }
+
+    fn mark(&mut self, addresses: Range<usize>, mark: u8) {
+        let end = addresses.end.min(self.marks.len());
+        for m in &mut self.marks[addresses.start.min(end)..end] {
+            *m |= mark;
+        }
+    }
+
+    fn is_touched(&self, address: usize) -> bool {
+        self.marks.get(address).is_some_and(|&m| m != 0)
+    }
+
+    fn marker(&self, address: usize) -> &'static str {
+        match (self.is_executed(address), self.is_read(address)) {
+            (true, true) => "XR",
+            (true, false) => "X",
+            (false, true) => "R",
+            (false, false) => "-",
+        }
+    }
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let percent = |count: usize| 100.0 * count as f64 / self.size.max(1) as f64;
+        write!(
+            f,
+            "{} ROM bytes: {} executed ({:.1}%), {} read as data ({:.1}%), \
+             {} never touched ({:.1}%)",
+            self.size,
+            self.executed,
+            percent(self.executed),
+            self.read,
+            percent(self.read),
+            self.untouched,
+            percent(self.untouched)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // JP 0x204, a sprite byte that gets read, CLS, and a never touched word
+    const ROM: [u8; 8] = [0x12, 0x04, 0xF0, 0x00, 0x00, 0xE0, 0x00, 0xEE];
+
+    fn coverage() -> Coverage {
+        let mut coverage = Coverage::new();
+        coverage.record_execution(0x200);
+        coverage.record_execution(0x204);
+        coverage.record_read(0x202, 1);
+        coverage
+    }
+
+    #[test]
+    fn it_summarizes_rom_coverage() {
+        assert_eq!(
+            coverage().summary(ROM.len()),
+            Summary {
+                size: 8,
+                executed: 4,
+                read: 1,
+                untouched: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn it_writes_an_annotated_disassembly() {
+        let mut output = Vec::new();
+        coverage().write_text(&mut output, &ROM).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().skip(2).collect();
+        assert_eq!(
+            lines,
+            [
+                "0x200  1204  X   JP 0x204",
+                "0x202  F0    R   ####....",
+                "0x203  00    -   ........",
+                "0x204  00E0  X   CLS",
+                "0x206  00EE  -   RET",
+            ]
+        );
+    }
+
+    #[test]
+    fn it_writes_an_html_coverage_map() {
+        let mut output = Vec::new();
+        coverage().write_html(&mut output, &ROM).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("<td class=\"x\" title=\"0x200\">12</td>"));
+        assert!(output.contains("<td class=\"r\" title=\"0x202\">F0</td>"));
+        assert!(output.contains("<td class=\"u\" title=\"0x207\">EE</td>"));
+    }
+}
@@ -0,0 +1,256 @@
+// Guesses the platform of ROMs the ROM database doesn't know, from the
+// instructions the analysis found reachable from the entry point. SUPER-CHIP
+// and XO-CHIP only opcodes give the platform away. For plain CHIP-8 ROMs, the
+// way shifts and FX55/FX65 are used hints at whether they were written for
+// the COSMAC VIP or for CHIP-48.
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::analysis::Analysis;
+use crate::chip8::Instruction;
+use crate::quirks::Quirks;
+use crate::romdb::RomDatabase;
+
+// Platform ids as used by the ROM database
+const ORIGINAL_CHIP8: &str = "originalChip8";
+const CHIP48: &str = "chip48";
+const SUPERCHIP: &str = "superchip";
+const XOCHIP: &str = "xochip";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Detection {
+    pub platform: &'static str,
+    pub quirks: Quirks,
+    pub evidence: Vec<Evidence>,
+}
+
+// A platform and why an instruction points to it
+type Hint = (&'static str, &'static str);
+
+// An instruction that points to a platform
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Evidence {
+    pub address: u16,
+    pub opcode: u16,
+    pub platform: &'static str,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for Evidence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#05x}: {:04X} {} ({})",
+            self.address, self.opcode, self.reason, self.platform
+        )
+    }
+}
+
+pub fn detect(analysis: &Analysis) -> Detection {
+    let mut evidence: Vec<Evidence> = analysis
+        .instructions()
+        .filter_map(|(address, opcode)| {
+            let (platform, reason) = extension(opcode)?;
+            Some(Evidence {
+                address,
+                opcode,
+                platform,
+                reason,
+            })
+        })
+        .collect();
+
+    let platform = if count(&evidence, XOCHIP) > 0 {
+        XOCHIP
+    } else if count(&evidence, SUPERCHIP) > 0 {
+        SUPERCHIP
+    } else {
+        evidence.extend(quirk_evidence(analysis));
+        // Most CHIP-8 ROMs date from the VIP, so that wins a tie
+        if count(&evidence, CHIP48) > count(&evidence, ORIGINAL_CHIP8) {
+            CHIP48
+        } else {
+            ORIGINAL_CHIP8
+        }
+    };
+
+    Detection {
+        platform,
+        quirks: RomDatabase::bundled()
+            .platform_quirks(platform)
+            .unwrap_or_default(),
+        evidence,
+    }
+}
+
+fn count(evidence: &[Evidence], platform: &str) -> usize {
+    evidence.iter().filter(|e| e.platform == platform).count()
+}
+
+// Opcodes that only exist on SUPER-CHIP (and XO-CHIP, which extends it) or
+// only on XO-CHIP
+fn extension(opcode: u16) -> Option<Hint> {
+    let found = match Instruction::from(opcode).nibbles {
+        (0x00, 0x00, 0x0C, _) => (SUPERCHIP, "scrolls down"),
+        (0x00, 0x00, 0x0F, 0x0B) => (SUPERCHIP, "scrolls right"),
+        (0x00, 0x00, 0x0F, 0x0C) => (SUPERCHIP, "scrolls left"),
+        (0x00, 0x00, 0x0F, 0x0D) => (SUPERCHIP, "exits the interpreter"),
+        (0x00, 0x00, 0x0F, 0x0E) => (SUPERCHIP, "switches to low resolution"),
+        (0x00, 0x00, 0x0F, 0x0F) => (SUPERCHIP, "switches to high resolution"),
+        (0x0D, _, _, 0x00) => (SUPERCHIP, "draws a 16x16 sprite"),
+        (0x0F, _, 0x03, 0x00) => (SUPERCHIP, "points I to a large font digit"),
+        (0x0F, _, 0x07 | 0x08, 0x05) => (SUPERCHIP, "uses the RPL user flags"),
+        (0x00, 0x00, 0x0D, _) => (XOCHIP, "scrolls up"),
+        (0x05, _, _, 0x02) => (XOCHIP, "saves a register range"),
+        (0x05, _, _, 0x03) => (XOCHIP, "loads a register range"),
+        (0x0F, 0x00, 0x00, 0x00) => (XOCHIP, "loads a 16-bit address into I"),
+        (0x0F, _, 0x00, 0x01) => (XOCHIP, "selects bitplanes"),
+        (0x0F, 0x00, 0x00, 0x02) => (XOCHIP, "loads an audio pattern"),
+        (0x0F, _, 0x03, 0x0A) => (XOCHIP, "sets the pitch"),
+        _ => return None,
+    };
+    Some(found)
+}
+
+fn quirk_evidence(analysis: &Analysis) -> Vec<Evidence> {
+    let mut evidence = Vec::new();
+    for (address, opcode) in analysis.instructions() {
+        let hint = match Instruction::from(opcode).nibbles {
+            (0x08, x, y, 0x06 | 0x0E) if x != y => shift_hint(y),
+            (0x0F, _, 0x05 | 0x06, 0x05) => load_store_hint(analysis, address),
+            _ => None,
+        };
+        if let Some((platform, reason)) = hint {
+            evidence.push(Evidence {
+                address,
+                opcode,
+                platform,
+                reason,
+            });
+        }
+    }
+    evidence
+}
+
+// CHIP-48 assemblers write SHR VX as 8X06, while VIP programs name the
+// register that gets shifted
+fn shift_hint(y: u8) -> Option<Hint> {
+    let hint = if y == 0 {
+        (CHIP48, "shifts VX with V0 as VY")
+    } else {
+        (ORIGINAL_CHIP8, "shifts VY into VX")
+    };
+    Some(hint)
+}
+
+fn load_store_hint(analysis: &Analysis, address: u16) -> Option<Hint> {
+    let hint = if next_load_store(analysis, address)? {
+        (CHIP48, "advances I with FX1E between loads and stores")
+    } else {
+        (ORIGINAL_CHIP8, "relies on loads and stores advancing I")
+    };
+    Some(hint)
+}
+
+// Whether execution can get from the FX55/FX65 at the address to another one
+// (or itself, in a loop) without I being set, and if so whether I gets
+// advanced with FX1E on the way. Calls are assumed to set I.
+fn next_load_store(analysis: &Analysis, address: u16) -> Option<bool> {
+    let mut visited = BTreeSet::new();
+    let mut worklist: Vec<(u16, bool)> = analysis
+        .successors(address)
+        .map(|next| (next, false))
+        .collect();
+    let mut result = None;
+    while let Some((address, advanced)) = worklist.pop() {
+        if !visited.insert((address, advanced)) {
+            continue;
+        }
+        let Some(opcode) = analysis.opcode(address) else {
+            continue;
+        };
+        let advanced = match Instruction::from(opcode).nibbles {
+            (0x0F, _, 0x05 | 0x06, 0x05) => {
+                result = Some(result.unwrap_or(false) || advanced);
+                continue;
+            }
+            (0x0A | 0x02, _, _, _)
+            | (0x0F, _, 0x02, 0x09)
+            | (0x0F, _, 0x03, 0x00)
+            | (0x0F, 0x00, 0x00, 0x00) => continue,
+            (0x0F, _, 0x01, 0x0E) => true,
+            _ => advanced,
+        };
+        let successors = analysis.successors(address);
+        worklist.extend(successors.map(|next| (next, advanced)));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis;
+
+    fn detect(rom: &[u8]) -> Detection {
+        super::detect(&analysis::analyze(rom).unwrap())
+    }
+
+    fn reasons(detection: &Detection) -> Vec<String> {
+        detection.evidence.iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn it_detects_extension_opcodes() {
+        // HIGH; JP 0x200
+        let detection = detect(&[0x00, 0xFF, 0x12, 0x00]);
+        assert_eq!(detection.platform, SUPERCHIP);
+        assert!(detection.quirks.jump_uses_vx);
+        assert_eq!(
+            reasons(&detection),
+            ["0x200: 00FF switches to high resolution (superchip)"]
+        );
+
+        let rom = [
+            0xF0, 0x00, 0x12, 0x34, // 0x200: LD I, long 0x1234
+            0x00, 0xC1, // 0x204: SCD 1
+            0x12, 0x06, // 0x206: JP 0x206
+        ];
+        let detection = detect(&rom);
+        assert_eq!(detection.platform, XOCHIP);
+        assert_eq!(detection.evidence.len(), 2);
+    }
+
+    #[test]
+    fn it_ignores_data_that_is_never_executed() {
+        // JP 0x200, then sprite bytes that look like 00FF
+        assert_eq!(detect(&[0x12, 0x00, 0x00, 0xFF]).platform, ORIGINAL_CHIP8);
+    }
+
+    #[test]
+    fn it_detects_vip_style_register_loops() {
+        // LD I, 0x300; LD V3, [I]; SE V0, 0; JP 0x202; JP 0x208
+        let rom = [0xA3, 0x00, 0xF3, 0x65, 0x30, 0x00, 0x12, 0x02, 0x12, 0x08];
+        let detection = detect(&rom);
+        assert_eq!(detection.platform, ORIGINAL_CHIP8);
+        let reason = "relies on loads and stores advancing I (originalChip8)";
+        assert_eq!(reasons(&detection), [format!("0x202: F365 {}", reason)]);
+    }
+
+    #[test]
+    fn it_detects_chip48_style_code() {
+        let rom = [
+            0x81, 0x06, // 0x200: SHR V1
+            0xA3, 0x00, // 0x202: LD I, 0x300
+            0xF3, 0x65, // 0x204: LD V3, [I]
+            0xF4, 0x1E, // 0x206: ADD I, V4
+            0x30, 0x00, // 0x208: SE V0, 0
+            0x12, 0x04, // 0x20A: JP 0x204
+            0x12, 0x0C, // 0x20C: JP 0x20C
+        ];
+        let detection = detect(&rom);
+        assert_eq!(detection.platform, CHIP48);
+        assert_eq!(detection.evidence.len(), 2);
+        assert!(!detection.quirks.shift_uses_vy);
+    }
+}
@@ -0,0 +1,181 @@
+use std::io::{self, Write};
+
+use crate::chip8::{Instruction, PROGRAM_LOAD_ADDRESS};
+
+// Returns the mnemonic for an opcode, in the syntax of Cowgod's CHIP-8
+// technical reference. Unknown opcodes come out as a DW data word.
+pub fn disassemble(opcode: u16) -> String {
+    let instruction = Instruction::from(opcode);
+    let x = instruction.x();
+    let y = instruction.y();
+    let nn = instruction.nn();
+    let nnn = instruction.nnn();
+
+    match instruction.nibbles {
+        (0x00, 0x00, 0x0E, 0x00) => "CLS".to_string(),
+        (0x00, 0x00, 0x0E, 0x0E) => "RET".to_string(),
+        (0x00, _, _, _) => format!("SYS {:#05x}", nnn),
+        (0x01, _, _, _) => format!("JP {:#05x}", nnn),
+        (0x02, _, _, _) => format!("CALL {:#05x}", nnn),
+        (0x03, _, _, _) => format!("SE V{:X}, {:#04x}", x, nn),
+        (0x04, _, _, _) => format!("SNE V{:X}, {:#04x}", x, nn),
+        (0x05, _, _, 0x00) => format!("SE V{:X}, V{:X}", x, y),
+        (0x06, _, _, _) => format!("LD V{:X}, {:#04x}", x, nn),
+        (0x07, _, _, _) => format!("ADD V{:X}, {:#04x}", x, nn),
+        (0x08, _, _, 0x00) => format!("LD V{:X}, V{:X}", x, y),
+        (0x08, _, _, 0x01) => format!("OR V{:X}, V{:X}", x, y),
+        (0x08, _, _, 0x02) => format!("AND V{:X}, V{:X}", x, y),
+        (0x08, _, _, 0x03) => format!("XOR V{:X}, V{:X}", x, y),
+        (0x08, _, _, 0x04) => format!("ADD V{:X}, V{:X}", x, y),
+        (0x08, _, _, 0x05) => format!("SUB V{:X}, V{:X}", x, y),
+        (0x08, _, _, 0x06) => format!("SHR V{:X}, V{:X}", x, y),
+        (0x08, _, _, 0x07) => format!("SUBN V{:X}, V{:X}", x, y),
+        (0x08, _, _, 0x0E) => format!("SHL V{:X}, V{:X}", x, y),
+        (0x09, _, _, 0x00) => format!("SNE V{:X}, V{:X}", x, y),
+        (0x0A, _, _, _) => format!("LD I, {:#05x}", nnn),
+        (0x0B, _, _, _) => format!("JP V0, {:#05x}", nnn),
+        (0x0C, _, _, _) => format!("RND V{:X}, {:#04x}", x, nn),
+        (0x0D, _, _, n) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        (0x0E, _, 0x09, 0x0E) => format!("SKP V{:X}", x),
+        (0x0E, _, 0x0A, 0x01) => format!("SKNP V{:X}", x),
+        (0x0F, _, 0x00, 0x07) => format!("LD V{:X}, DT", x),
+        (0x0F, _, 0x00, 0x0A) => format!("LD V{:X}, K", x),
+        (0x0F, _, 0x01, 0x05) => format!("LD DT, V{:X}", x),
+        (0x0F, _, 0x01, 0x08) => format!("LD ST, V{:X}", x),
+        (0x0F, _, 0x01, 0x0E) => format!("ADD I, V{:X}", x),
+        (0x0F, _, 0x02, 0x09) => format!("LD F, V{:X}", x),
+        (0x0F, 0x00, 0x00, 0x02) => "AUDIO".to_string(),
+        (0x0F, _, 0x03, 0x0A) => format!("PITCH V{:X}", x),
+        (0x0F, _, 0x03, 0x03) => format!("LD B, V{:X}", x),
+        (0x0F, _, 0x05, 0x05) => format!("LD [I], V{:X}", x),
+        (0x0F, _, 0x06, 0x05) => format!("LD V{:X}, [I]", x),
+        _ => format!("DW {:#06x}", opcode),
+    }
+}
+
+// Disassembles a whole ROM, one word per line, into source for the assembler.
+// Addresses and opcodes go into comments.
+pub fn write_listing(mut writer: impl Write, rom: &[u8]) -> io::Result<()> {
+    for (i, word) in rom.chunks(2).enumerate() {
+        let address = PROGRAM_LOAD_ADDRESS + i * 2;
+        match *word {
+            [high, low] => {
+                let opcode = (high as u16) << 8 | low as u16;
+                let mnemonic = disassemble(opcode);
+                writeln!(
+                    writer,
+                    "    {:<20}; {:#05x}  {:04X}",
+                    mnemonic, address, opcode
+                )?;
+            }
+            [byte] => {
+                let data = format!("DB {:#04x}", byte);
+                writeln!(writer, "    {:<20}; {:#05x}  {:02X}", data, address, byte)?;
+            }
+            _ => unreachable!(),
+        }
+    }
+    Ok(())
+}
+
+// The opcode pattern an instruction matches, like 8XY4, for grouping
+// instructions by what they do. SUPER-CHIP and XO-CHIP opcodes get their own
+// classes even though they can't be run.
+pub fn opcode_class(opcode: u16) -> &'static str {
+    match Instruction::from(opcode).nibbles {
+        (0x00, 0x00, 0x0E, 0x00) => "00E0",
+        (0x00, 0x00, 0x0E, 0x0E) => "00EE",
+        (0x00, 0x00, 0x0C, _) => "00CN",
+        (0x00, 0x00, 0x0D, _) => "00DN",
+        (0x00, 0x00, 0x0F, 0x0B) => "00FB",
+        (0x00, 0x00, 0x0F, 0x0C) => "00FC",
+        (0x00, 0x00, 0x0F, 0x0D) => "00FD",
+        (0x00, 0x00, 0x0F, 0x0E) => "00FE",
+        (0x00, 0x00, 0x0F, 0x0F) => "00FF",
+        (0x00, _, _, _) => "0NNN",
+        (0x01, _, _, _) => "1NNN",
+        (0x02, _, _, _) => "2NNN",
+        (0x03, _, _, _) => "3XNN",
+        (0x04, _, _, _) => "4XNN",
+        (0x05, _, _, 0x00) => "5XY0",
+        (0x05, _, _, 0x02) => "5XY2",
+        (0x05, _, _, 0x03) => "5XY3",
+        (0x06, _, _, _) => "6XNN",
+        (0x07, _, _, _) => "7XNN",
+        (0x08, _, _, 0x00) => "8XY0",
+        (0x08, _, _, 0x01) => "8XY1",
+        (0x08, _, _, 0x02) => "8XY2",
+        (0x08, _, _, 0x03) => "8XY3",
+        (0x08, _, _, 0x04) => "8XY4",
+        (0x08, _, _, 0x05) => "8XY5",
+        (0x08, _, _, 0x06) => "8XY6",
+        (0x08, _, _, 0x07) => "8XY7",
+        (0x08, _, _, 0x0E) => "8XYE",
+        (0x09, _, _, 0x00) => "9XY0",
+        (0x0A, _, _, _) => "ANNN",
+        (0x0B, _, _, _) => "BNNN",
+        (0x0C, _, _, _) => "CXNN",
+        (0x0D, _, _, _) => "DXYN",
+        (0x0E, _, 0x09, 0x0E) => "EX9E",
+        (0x0E, _, 0x0A, 0x01) => "EXA1",
+        (0x0F, _, 0x00, 0x07) => "FX07",
+        (0x0F, _, 0x00, 0x0A) => "FX0A",
+        (0x0F, _, 0x01, 0x05) => "FX15",
+        (0x0F, _, 0x01, 0x08) => "FX18",
+        (0x0F, _, 0x01, 0x0E) => "FX1E",
+        (0x0F, _, 0x02, 0x09) => "FX29",
+        (0x0F, 0x00, 0x00, 0x00) => "F000",
+        (0x0F, _, 0x00, 0x01) => "FX01",
+        (0x0F, 0x00, 0x00, 0x02) => "F002",
+        (0x0F, _, 0x03, 0x00) => "FX30",
+        (0x0F, _, 0x03, 0x0A) => "FX3A",
+        (0x0F, _, 0x03, 0x03) => "FX33",
+        (0x0F, _, 0x05, 0x05) => "FX55",
+        (0x0F, _, 0x06, 0x05) => "FX65",
+        (0x0F, _, 0x07, 0x05) => "FX75",
+        (0x0F, _, 0x08, 0x05) => "FX85",
+        _ => "invalid",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_disassembles_instructions() {
+        assert_eq!(disassemble(0x00E0), "CLS");
+        assert_eq!(disassemble(0x1204), "JP 0x204");
+        assert_eq!(disassemble(0x6A12), "LD VA, 0x12");
+        assert_eq!(disassemble(0x8AB4), "ADD VA, VB");
+        assert_eq!(disassemble(0xD015), "DRW V0, V1, 5");
+        assert_eq!(disassemble(0xF065), "LD V0, [I]");
+        assert_eq!(disassemble(0xF002), "AUDIO");
+    }
+
+    #[test]
+    fn it_writes_listings() {
+        let mut listing = Vec::new();
+        write_listing(&mut listing, &[0x00, 0xE0, 0x12]).unwrap();
+        assert_eq!(
+            String::from_utf8(listing).unwrap(),
+            "    CLS                 ; 0x200  00E0\n    DB 0x12             ; 0x202  12\n"
+        );
+    }
+
+    #[test]
+    fn it_classifies_opcodes() {
+        assert_eq!(opcode_class(0x8AB4), "8XY4");
+        assert_eq!(opcode_class(0xF31E), "FX1E");
+        assert_eq!(opcode_class(0x5121), "invalid");
+        assert_eq!(opcode_class(0x00C4), "00CN");
+        assert_eq!(opcode_class(0xF000), "F000");
+        assert_eq!(opcode_class(0x0123), "0NNN");
+    }
+
+    #[test]
+    fn it_disassembles_unknown_opcodes_as_data() {
+        assert_eq!(disassemble(0x5121), "DW 0x5121");
+        assert_eq!(disassemble(0xFFFF), "DW 0xffff");
+    }
+}
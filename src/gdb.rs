@@ -0,0 +1,514 @@
+// A stub for GDB's remote serial protocol, so debuggers can inspect and
+// control a running ROM:
+// https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+use std::collections::BTreeSet;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::ops::Range;
+
+use crate::chip8::Chip8;
+
+// Register numbers as used by `p`/`P` and the target description: V0-VF,
+// then I, PC and SP. `g`/`G` transfer them in this order, little endian.
+const REG_I: usize = 16;
+const REG_PC: usize = 17;
+const REG_SP: usize = 18;
+const NUM_REGISTERS: usize = 19;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chip8.core">
+    <reg name="v0" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="v1" bitsize="8" type="uint8"/>
+    <reg name="v2" bitsize="8" type="uint8"/>
+    <reg name="v3" bitsize="8" type="uint8"/>
+    <reg name="v4" bitsize="8" type="uint8"/>
+    <reg name="v5" bitsize="8" type="uint8"/>
+    <reg name="v6" bitsize="8" type="uint8"/>
+    <reg name="v7" bitsize="8" type="uint8"/>
+    <reg name="v8" bitsize="8" type="uint8"/>
+    <reg name="v9" bitsize="8" type="uint8"/>
+    <reg name="va" bitsize="8" type="uint8"/>
+    <reg name="vb" bitsize="8" type="uint8"/>
+    <reg name="vc" bitsize="8" type="uint8"/>
+    <reg name="vd" bitsize="8" type="uint8"/>
+    <reg name="ve" bitsize="8" type="uint8"/>
+    <reg name="vf" bitsize="8" type="uint8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+// Stop replies, the numbers are GDB's signal numbers
+const STOPPED_BY_INTERRUPT: &str = "S02";
+const STOPPED_BY_TRAP: &str = "S05";
+
+const INTERRUPT: u8 = 0x03;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Session {
+    Attached,
+    // The debugger detached or went away, the machine should keep running
+    Detached,
+    // The debugger asked to kill the program
+    Killed,
+}
+
+enum Input {
+    Packet(String),
+    Interrupt,
+}
+
+pub struct GdbStub {
+    stream: TcpStream,
+    input: Vec<u8>,
+    breakpoints: BTreeSet<u16>,
+    running: bool,
+    // Set on resume, so continuing from a breakpoint doesn't stop right away
+    step_over_breakpoint: bool,
+}
+
+impl GdbStub {
+    // Blocks until a debugger connects to the given port on localhost
+    pub fn listen(port: u16) -> Result<Self, String> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+            .map_err(|e| format!("Cannot listen on port {}: {}", port, e))?;
+        let (stream, _) = listener
+            .accept()
+            .map_err(|e| format!("Cannot accept debugger connection: {}", e))?;
+        Self::new(stream)
+    }
+
+    // The machine starts out halted, as debuggers expect after attaching
+    pub fn new(stream: TcpStream) -> Result<Self, String> {
+        stream.set_nonblocking(true).map_err(|e| e.to_string())?;
+        stream.set_nodelay(true).map_err(|e| e.to_string())?;
+        Ok(GdbStub {
+            stream,
+            input: Vec::new(),
+            breakpoints: BTreeSet::new(),
+            running: false,
+            step_over_breakpoint: false,
+        })
+    }
+
+    // Handles pending debugger requests without blocking, then runs a frame
+    // like `Chip8::run_frame` unless the debugger halted the machine. Hitting
+    // a breakpoint ends the frame early and skips the timer update.
+    pub fn run_frame(&mut self, chip8: &mut Chip8, ticks: usize) -> Result<Session, String> {
+        if !self.receive()? {
+            return Ok(Session::Detached);
+        }
+        while let Some(input) = self.next_input() {
+            let packet = match input {
+                Input::Interrupt => {
+                    if self.running {
+                        self.running = false;
+                        self.send(STOPPED_BY_INTERRUPT)?;
+                    }
+                    continue;
+                }
+                Input::Packet(packet) => packet,
+            };
+            match packet.as_str() {
+                "D" => {
+                    self.send("OK")?;
+                    return Ok(Session::Detached);
+                }
+                "k" => return Ok(Session::Killed),
+                _ => {
+                    if let Some(response) = self.handle_packet(chip8, &packet) {
+                        self.send(&response)?;
+                    }
+                }
+            }
+        }
+
+        if !self.running {
+            return Ok(Session::Attached);
+        }
+        for _ in 0..ticks {
+            let step_over = std::mem::replace(&mut self.step_over_breakpoint, false);
+            if !step_over && self.breakpoints.contains(&chip8.pc()) {
+                self.running = false;
+                self.send(STOPPED_BY_TRAP)?;
+                return Ok(Session::Attached);
+            }
+            chip8.exec();
+            if chip8.is_waiting_for_key() {
+                break;
+            }
+        }
+        chip8.update_timers();
+        Ok(Session::Attached)
+    }
+
+    // Returns the response, or None for requests answered later (continue)
+    fn handle_packet(&mut self, chip8: &mut Chip8, packet: &str) -> Option<String> {
+        let (command, args) = packet.split_at_checked(1).unwrap_or_default();
+        let response = match command {
+            "?" => STOPPED_BY_TRAP.to_string(),
+            "g" => encode_hex(&read_registers(chip8)),
+            "G" => result(
+                decode_hex(args)
+                    .ok_or_else(|| "Malformed register data".to_string())
+                    .and_then(|bytes| write_registers(chip8, &bytes)),
+            ),
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(reg) if reg < NUM_REGISTERS => {
+                    let (offset, size) = register_location(reg);
+                    encode_hex(&read_registers(chip8)[offset..offset + size])
+                }
+                _ => error(),
+            },
+            "P" => result(write_register(chip8, args)),
+            "m" => match parse_range(args).and_then(|(start, len)| ram_range(chip8, start, len)) {
+                Some(range) => encode_hex(&chip8.ram()[range]),
+                None => error(),
+            },
+            "M" => result(write_memory(chip8, args)),
+            "Z" | "z" => match args.strip_prefix("0,").and_then(parse_range) {
+                Some((address, _)) => match u16::try_from(address) {
+                    Ok(address) => {
+                        if command == "Z" {
+                            self.breakpoints.insert(address);
+                        } else {
+                            self.breakpoints.remove(&address);
+                        }
+                        "OK".to_string()
+                    }
+                    Err(_) => error(),
+                },
+                // Only software breakpoints are supported
+                None => String::new(),
+            },
+            "s" | "c" => {
+                let resume_at = match args {
+                    "" => Ok(()),
+                    _ => u16::from_str_radix(args, 16)
+                        .map_err(|e| e.to_string())
+                        .and_then(|pc| chip8.set_pc(pc)),
+                };
+                if resume_at.is_err() {
+                    return Some(error());
+                }
+                if command == "s" {
+                    chip8.exec();
+                    STOPPED_BY_TRAP.to_string()
+                } else {
+                    self.running = true;
+                    self.step_over_breakpoint = true;
+                    return None;
+                }
+            }
+            "H" => "OK".to_string(),
+            "q" => query(args),
+            // Unsupported requests get an empty response
+            _ => String::new(),
+        };
+        Some(response)
+    }
+
+    // Reads whatever the debugger sent so far, returns false once the
+    // connection was closed
+    fn receive(&mut self) -> Result<bool, String> {
+        let mut buffer = [0; 1024];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Ok(false),
+                Ok(n) => self.input.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(true),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(format!("Debugger connection failed: {}", e)),
+            }
+        }
+    }
+
+    // Takes the next complete packet off the input buffer. Valid packets get
+    // acknowledged, the debugger retransmits corrupted ones.
+    fn next_input(&mut self) -> Option<Input> {
+        loop {
+            match *self.input.first()? {
+                INTERRUPT => {
+                    self.input.remove(0);
+                    return Some(Input::Interrupt);
+                }
+                b'$' => {
+                    let end = self.input.iter().position(|&b| b == b'#')?;
+                    if self.input.len() < end + 3 {
+                        return None;
+                    }
+                    let packet: Vec<u8> = self.input.drain(..end + 3).collect();
+                    let data = &packet[1..end];
+                    let valid = std::str::from_utf8(&packet[end + 1..])
+                        .ok()
+                        .and_then(|checksum| u8::from_str_radix(checksum, 16).ok())
+                        == Some(checksum(data));
+                    let ack: &[u8] = if valid { b"+" } else { b"-" };
+                    if self.write(ack).is_err() {
+                        return None;
+                    }
+                    if valid && !data.is_empty() {
+                        return Some(Input::Packet(String::from_utf8_lossy(data).into()));
+                    }
+                }
+                // Acknowledgements of our responses and line noise
+                _ => {
+                    self.input.remove(0);
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> Result<(), String> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        self.write(packet.as_bytes())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), String> {
+        // Responses are small, so briefly blocking is fine and saves retrying
+        // partial writes
+        let result = self
+            .stream
+            .set_nonblocking(false)
+            .and_then(|_| self.stream.write_all(bytes))
+            .and_then(|_| self.stream.set_nonblocking(true));
+        result.map_err(|e| format!("Debugger connection failed: {}", e))
+    }
+}
+
+fn query(args: &str) -> String {
+    if args.starts_with("Supported") {
+        return "PacketSize=4000;qXfer:features:read+".to_string();
+    }
+    if args == "Attached" {
+        return "1".to_string();
+    }
+    match args
+        .strip_prefix("Xfer:features:read:target.xml:")
+        .and_then(parse_range)
+    {
+        Some((offset, len)) => {
+            let start = offset.min(TARGET_XML.len());
+            let end = (start + len).min(TARGET_XML.len());
+            let marker = if end == TARGET_XML.len() { 'l' } else { 'm' };
+            format!("{}{}", marker, &TARGET_XML[start..end])
+        }
+        None => String::new(),
+    }
+}
+
+fn read_registers(chip8: &Chip8) -> Vec<u8> {
+    let mut bytes = chip8.v_registers().to_vec();
+    bytes.extend_from_slice(&chip8.i_register().to_le_bytes());
+    bytes.extend_from_slice(&chip8.pc().to_le_bytes());
+    bytes.push(chip8.sp());
+    bytes
+}
+
+fn write_registers(chip8: &mut Chip8, bytes: &[u8]) -> Result<(), String> {
+    let (v, rest) = bytes
+        .split_at_checked(REG_I)
+        .ok_or_else(|| "Register data too short".to_string())?;
+    let [i_low, i_high, pc_low, pc_high, sp] = rest else {
+        return Err("Register data has the wrong size".to_string());
+    };
+    chip8.set_pc(u16::from_le_bytes([*pc_low, *pc_high]))?;
+    chip8.set_sp(*sp)?;
+    chip8.set_i_register(u16::from_le_bytes([*i_low, *i_high]));
+    chip8.v_registers_mut().copy_from_slice(v);
+    Ok(())
+}
+
+// Handles `P<reg>=<value>`
+fn write_register(chip8: &mut Chip8, args: &str) -> Result<(), String> {
+    let (reg, value) = args
+        .split_once('=')
+        .ok_or_else(|| "Malformed register write".to_string())?;
+    let reg = usize::from_str_radix(reg, 16).map_err(|e| e.to_string())?;
+    let value = decode_hex(value).ok_or_else(|| "Malformed register value".to_string())?;
+    if reg >= NUM_REGISTERS || value.len() != register_location(reg).1 {
+        return Err(format!("Cannot write register {}", reg));
+    }
+    let word = || u16::from_le_bytes([value[0], value[1]]);
+    match reg {
+        REG_I => chip8.set_i_register(word()),
+        REG_PC => chip8.set_pc(word())?,
+        REG_SP => chip8.set_sp(value[0])?,
+        v => chip8.v_registers_mut()[v] = value[0],
+    }
+    Ok(())
+}
+
+// Handles `M<address>,<length>:<data>`
+fn write_memory(chip8: &mut Chip8, args: &str) -> Result<(), String> {
+    let (range, data) = args
+        .split_once(':')
+        .ok_or_else(|| "Malformed memory write".to_string())?;
+    let (start, len) = parse_range(range).ok_or_else(|| "Malformed address".to_string())?;
+    let data = decode_hex(data).ok_or_else(|| "Malformed memory data".to_string())?;
+    match ram_range(chip8, start, len) {
+        Some(range) if data.len() == len => {
+            chip8.ram_mut()[range].copy_from_slice(&data);
+            Ok(())
+        }
+        _ => Err(format!("Cannot write {} bytes at {:#x}", len, start)),
+    }
+}
+
+fn ram_range(chip8: &Chip8, start: usize, len: usize) -> Option<Range<usize>> {
+    let end = start.checked_add(len)?;
+    (end <= chip8.ram().len()).then_some(start..end)
+}
+
+// Byte offset and size of a register in the `g` packet
+fn register_location(reg: usize) -> (usize, usize) {
+    match reg {
+        REG_I => (REG_I, 2),
+        REG_PC => (REG_I + 2, 2),
+        REG_SP => (REG_I + 4, 1),
+        v => (v, 1),
+    }
+}
+
+// Parses `<hex>,<hex>` as used for addresses/lengths and offsets/lengths
+fn parse_range(args: &str) -> Option<(usize, usize)> {
+    let (start, len) = args.split_once(',')?;
+    Some((
+        usize::from_str_radix(start, 16).ok()?,
+        usize::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+fn result(result: Result<(), String>) -> String {
+    match result {
+        Ok(()) => "OK".to_string(),
+        Err(_) => error(),
+    }
+}
+
+fn error() -> String {
+    "E01".to_string()
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &b| sum.wrapping_add(b))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::{SilentSpeaker, TICKS_PER_FRAME};
+    use std::thread;
+
+    // A blocking debugger that sends one request at a time
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn request(&mut self, data: &str) -> String {
+            let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+            self.stream.write_all(packet.as_bytes()).unwrap();
+
+            let mut response = Vec::new();
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], b'+');
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                response.push(byte[0]);
+                if response.len() > 3 && response[response.len() - 3] == b'#' {
+                    break;
+                }
+            }
+            self.stream.write_all(b"+").unwrap();
+            String::from_utf8(response[1..response.len() - 3].to_vec()).unwrap()
+        }
+    }
+
+    #[test]
+    fn it_serves_a_scripted_debugger_session() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+            stream.set_nodelay(true).unwrap();
+            let mut gdb = Client { stream };
+            assert_eq!(gdb.request("?"), "S05");
+            assert!(gdb.request("qSupported").contains("qXfer:features:read+"));
+            let xml = gdb.request("qXfer:features:read:target.xml:0,fff");
+            assert!(xml.starts_with("l<?xml"));
+
+            assert_eq!(gdb.request("s"), "S05");
+            assert_eq!(gdb.request("s"), "S05");
+            // V0 = 0x12, I = 0x300, PC = 0x204, SP = 0
+            let registers = format!("12{}0003040200", "00".repeat(15));
+            assert_eq!(gdb.request("g"), registers);
+
+            assert_eq!(gdb.request("Z0,206,2"), "OK");
+            assert_eq!(gdb.request("c"), "S05");
+            assert_eq!(gdb.request("p11"), "0602");
+            assert_eq!(gdb.request("p0"), "13");
+            // Continuing stops at the breakpoint again after one loop
+            assert_eq!(gdb.request("c"), "S05");
+            assert_eq!(gdb.request("p0"), "14");
+            assert_eq!(gdb.request("z0,206,2"), "OK");
+            assert_eq!(gdb.request("Z0,10206,2"), "E01");
+
+            assert_eq!(gdb.request("m200,4"), "6012a300");
+            assert_eq!(gdb.request("M300,2:abcd"), "OK");
+            assert_eq!(gdb.request("m300,2"), "abcd");
+            assert_eq!(gdb.request("mfff,2"), "E01");
+
+            assert_eq!(gdb.request(&format!("G42{}", &registers[2..])), "OK");
+            assert_eq!(gdb.request("p0"), "42");
+            assert_eq!(gdb.request("P11=0010"), "E01");
+            assert_eq!(gdb.request("D"), "OK");
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut stub = GdbStub::new(stream).unwrap();
+        let mut chip8 = Chip8::new(Box::new(SilentSpeaker));
+        // LD V0, 0x12; LD I, 0x300; ADD V0, 1; JP 0x204
+        chip8
+            .load_rom(vec![0x60, 0x12, 0xA3, 0x00, 0x70, 0x01, 0x12, 0x04])
+            .unwrap();
+
+        let session = loop {
+            match stub.run_frame(&mut chip8, TICKS_PER_FRAME).unwrap() {
+                Session::Attached => thread::yield_now(),
+                session => break session,
+            }
+        };
+        client.join().unwrap();
+        assert_eq!(session, Session::Detached);
+        assert_eq!(chip8.v_registers()[0], 0x42);
+    }
+
+    #[test]
+    fn it_encodes_and_decodes_hex() {
+        assert_eq!(encode_hex(&[0x00, 0xAB, 0x12]), "00ab12");
+        assert_eq!(decode_hex("00ab12"), Some(vec![0x00, 0xAB, 0x12]));
+        assert_eq!(decode_hex("0"), None);
+        assert_eq!(decode_hex("zz"), None);
+    }
+}
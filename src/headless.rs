@@ -0,0 +1,99 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use debug_print::debug_eprintln;
+
+use chip_8::chip8::{self, FRAME_DURATION, SilentSpeaker};
+use chip_8::recorder::{GifRecorder, RawFrameWriter};
+use chip_8::synth::ToneSettings;
+use chip_8::wav_speaker::WavSpeaker;
+
+use crate::config::Config;
+use crate::{DEFAULT_SCALE, HeadlessOptions};
+
+// Runs the ROM for a fixed number of frames as fast as possible, without
+// opening a window or producing sound
+pub fn run(options: &HeadlessOptions) -> Result<(), String> {
+    // Two outputs on stdout would interleave
+    let on_stdout = [&options.record, &options.raw_frames, &options.wav]
+        .into_iter()
+        .flatten()
+        .filter(|path| is_stdout(path))
+        .count();
+    if on_stdout > 1 {
+        return Err("Only one of --record, --raw-frames and --wav can write to stdout".to_string());
+    }
+
+    let emulator = &options.emulator;
+    // Headless runs ignore the config file, so they are reproducible
+    let scale = emulator.scale.unwrap_or(DEFAULT_SCALE) as usize;
+    let tone = emulator.tone(ToneSettings::default());
+    let wav = options.wav.as_ref().map(|_| WavSpeaker::new(tone));
+    let mut chip8 = match &wav {
+        Some(speaker) => chip8::Chip8::new(Box::new(speaker.clone())),
+        None => chip8::Chip8::new(Box::new(SilentSpeaker)),
+    };
+    let settings = crate::setup_chip8(&mut chip8, emulator, &Config::default())?;
+    let palette = settings.palette;
+    let mut gif = match &options.record {
+        Some(path) => Some(GifRecorder::new(
+            create_output(path)?,
+            scale,
+            &palette,
+            emulator.record_changes_only,
+        )?),
+        None => None,
+    };
+    let mut raw_frames = match &options.raw_frames {
+        Some(path) => Some(RawFrameWriter::new(create_output(path)?, scale, &palette)),
+        None => None,
+    };
+
+    for _ in 0..options.frames {
+        chip8.run_frame(settings.ticks_per_frame);
+
+        if let Some(gif) = gif.as_mut() {
+            gif.capture(chip8.screen(), FRAME_DURATION)?;
+        }
+        if let Some(raw_frames) = raw_frames.as_mut() {
+            raw_frames.capture(chip8.screen())?;
+        }
+    }
+    debug_eprintln!("Ran {} frames", options.frames);
+
+    if let Some(gif) = gif {
+        gif.finish()?;
+    }
+    if let Some(raw_frames) = raw_frames {
+        raw_frames.finish()?;
+    }
+
+    if let (Some(speaker), Some(path)) = (&wav, &options.wav) {
+        speaker.write_wav(create_output(path)?)?;
+        debug_eprintln!("Sound active during frames {:?}", speaker.tones());
+    }
+
+    if let Some(path) = &options.screenshot {
+        chip8.save_screenshot(path, scale, &palette)?;
+        debug_eprintln!("Saved screenshot to {}", path.display());
+    }
+
+    crate::finish_chip8(&chip8, emulator)
+}
+
+fn is_stdout(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+// Opens a file for writing, or stdout when the path is "-". Diagnostics go to
+// stderr so they don't end up in the output.
+fn create_output(path: &Path) -> Result<Box<dyn Write>, String> {
+    if is_stdout(path) {
+        return Ok(Box::new(BufWriter::new(io::stdout().lock())));
+    }
+
+    let file =
+        File::create(path).map_err(|e| format!("Cannot create {}: {}", path.display(), e))?;
+    Ok(Box::new(BufWriter::new(file)))
+}
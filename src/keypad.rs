@@ -0,0 +1,80 @@
+use std::fmt;
+
+use crate::chip8::NUM_KEYS;
+
+// A key on the hexadecimal keypad, 0x0 to 0xF
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Key(u8);
+
+impl Key {
+    pub const fn new(value: u8) -> Option<Key> {
+        if (value as usize) < NUM_KEYS {
+            Some(Key(value))
+        } else {
+            None
+        }
+    }
+
+    // Like the COSMAC VIP keypad latch, only the low nibble of a register
+    // value selects the key
+    pub const fn from_low_nibble(value: u8) -> Key {
+        Key(value & 0x0F)
+    }
+
+    pub const fn value(self) -> u8 {
+        self.0
+    }
+
+    pub const fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    pub fn all() -> impl Iterator<Item = Key> {
+        (0..NUM_KEYS as u8).map(Key)
+    }
+}
+
+impl TryFrom<u8> for Key {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Key::new(value).ok_or_else(|| format!("Invalid key: {}", value))
+    }
+}
+
+impl TryFrom<usize> for Key {
+    type Error = String;
+
+    fn try_from(value: usize) -> Result<Self, Self::Error> {
+        u8::try_from(value)
+            .ok()
+            .and_then(Key::new)
+            .ok_or_else(|| format!("Invalid key: {}", value))
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:X}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_only_accepts_keypad_values() {
+        assert_eq!(Key::new(0xF).map(Key::value), Some(0xF));
+        assert_eq!(Key::new(0x10), None);
+        assert!(Key::try_from(16usize).is_err());
+        assert!(Key::try_from(300usize).is_err());
+        assert_eq!(Key::try_from(10usize).unwrap().to_string(), "A");
+    }
+
+    #[test]
+    fn it_maps_register_values_to_keys_by_their_low_nibble() {
+        assert_eq!(Key::from_low_nibble(0x1B), Key::new(0xB).unwrap());
+        assert_eq!(Key::all().count(), NUM_KEYS);
+    }
+}
@@ -1,4 +1,23 @@
+pub mod analysis;
+pub mod asm;
 pub mod chip8;
+pub mod coverage;
+pub mod detect;
+pub mod disasm;
+// Sockets aren't available in browsers
+#[cfg(not(target_arch = "wasm32"))]
+pub mod gdb;
+pub mod keypad;
+pub mod palette;
+pub mod phosphor;
+pub mod profiler;
+pub mod quirks;
+pub mod recorder;
+pub mod romdb;
+pub mod screenshot;
+pub mod synth;
+pub mod trace;
+pub mod wav_speaker;
 
 #[cfg(feature = "wasm")]
 pub mod wasm;
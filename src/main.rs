@@ -1,56 +1,689 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::str::FromStr;
 use std::{env, fs, thread, time};
 
-use debug_print::{debug_eprintln, debug_print, debug_println};
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use debug_print::{debug_eprint, debug_eprintln, debug_print, debug_println};
 use sdl2::event::Event;
 use sdl2::keyboard::Scancode;
-use sdl2::pixels::Color;
+use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::rect::Rect;
+use sdl2::render::{Canvas, Texture};
+use sdl2::video::Window;
 
+use chip_8::analysis::{self, Severity};
+use chip_8::asm;
 use chip_8::chip8;
+use chip_8::coverage::Coverage;
+use chip_8::detect;
+use chip_8::disasm;
+use chip_8::gdb::{GdbStub, Session};
+use chip_8::keypad::Key;
+use chip_8::palette::Palette;
+use chip_8::phosphor::PhosphorFilter;
+use chip_8::profiler::Profiler;
+use chip_8::quirks::{self, Quirks};
+use chip_8::recorder::GifRecorder;
+use chip_8::romdb::{self, RomDatabase};
+use chip_8::screenshot;
+use chip_8::synth::{MAX_PITCH, MIN_PITCH, ToneSettings, Waveform};
+use chip_8::trace::Tracer;
+use config::{Config, RomConfig};
 
+mod config;
+mod headless;
 mod sdl_speaker;
 
-// Each CHIP-8 pixel gets rendered as a 10x10 square
-const SCALE_FACTOR: u32 = 10;
-const WINDOW_WIDTH: u32 = chip8::DISPLAY_WIDTH as u32 * SCALE_FACTOR;
-const WINDOW_HEIGHT: u32 = chip8::DISPLAY_HEIGHT as u32 * SCALE_FACTOR;
+// Each CHIP-8 pixel gets rendered as a 10x10 square unless --scale or the
+// config file say otherwise
+const DEFAULT_SCALE: u32 = 10;
 const WINDOW_TITLE: &str = "Rust CHIP-8";
 
-const TARGET_FRAME_TIME: time::Duration = time::Duration::from_micros(16667); // ~60 Hz
+const TARGET_FRAME_TIME: time::Duration = chip8::FRAME_DURATION;
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        println!("USAGE: {} <path to ROM>", &args[0]);
-        std::process::exit(0);
+// Border drawn around the screen while the sound timer is active
+const SOUND_INDICATOR_COLOR: Color = Color::RGB(255, 176, 0);
+
+// Pitch hotkeys move the tone up or down a semitone
+const SEMITONE: f32 = 1.059_463_1;
+const VOLUME_STEP: f32 = 0.05;
+
+#[derive(Parser)]
+#[command(
+    name = "chip-8",
+    version,
+    about = "A CHIP-8 emulator",
+    after_help = "Running `chip-8 [OPTIONS] <ROM>` is short for `chip-8 run [OPTIONS] <ROM>`.",
+    arg_required_else_help = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Run a ROM in a window")]
+    Run(RunOptions),
+    #[command(about = "Run a ROM for a number of frames without a window")]
+    Headless(HeadlessOptions),
+    #[command(about = "Disassemble a ROM into source for asm")]
+    Disasm {
+        #[arg(value_name = "ROM")]
+        rom_path: PathBuf,
+    },
+    #[command(about = "Assemble a ROM")]
+    Asm {
+        source: PathBuf,
+        #[arg(
+            short,
+            long,
+            value_name = "file",
+            help = "Where to write the ROM [default: the source with a .ch8 extension]"
+        )]
+        output: Option<PathBuf>,
+    },
+    #[command(about = "Print size, checksums, platform and statistics of a ROM")]
+    Info {
+        #[arg(value_name = "ROM")]
+        rom_path: PathBuf,
+    },
+    #[command(about = "Check a ROM for likely bugs, fails if errors are found")]
+    Lint(LintOptions),
+}
+
+// Options for running a ROM, windowed or headless
+#[derive(Args)]
+struct Options {
+    #[arg(value_name = "ROM")]
+    rom_path: String,
+    #[arg(long, value_name = "n", value_parser = clap::value_parser!(u32).range(1..=64),
+          help = "Size of a CHIP-8 pixel in window or output pixels [default: 10]")]
+    scale: Option<u32>,
+    #[arg(long, alias = "ticks", value_name = "n",
+          value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+          help = "Instructions per frame [default: 10 or the ROM database's]")]
+    speed: Option<usize>,
+    #[arg(long = "quirk", value_name = "name[=on|off]", value_parser = quirks::parse_quirk,
+          help = "Turn a quirk on or off, see below")]
+    // Set by name in command line order, on top of the ROM database's or the
+    // platform's quirks
+    quirks: Vec<(String, bool)>,
+    #[arg(
+        long,
+        value_name = "id",
+        help = "Use the quirks of a platform from the ROM database, e.g. chip48"
+    )]
+    platform: Option<String>,
+    #[arg(
+        long,
+        value_name = "bg,fg",
+        help = "Background and foreground colors, e.g. 000000,ffffff"
+    )]
+    palette: Option<Palette>,
+    #[arg(long, help = "Don't look up settings in the ROM database")]
+    no_rom_db: bool,
+    #[arg(long, value_name = "n", help = "Seed for CXNN's random numbers")]
+    seed: Option<u64>,
+    #[arg(long, value_name = "hz", value_parser = parse_pitch, help = "Beeper frequency")]
+    pitch: Option<f32>,
+    #[arg(long, value_name = "0-1", value_parser = parse_volume, help = "Beeper volume")]
+    volume: Option<f32>,
+    #[arg(
+        long,
+        value_name = "name",
+        help = "Beeper waveform: square, sine, triangle or noise"
+    )]
+    waveform: Option<Waveform>,
+    #[arg(long, help = "Start with sound muted")]
+    mute: bool,
+    // Instruction trace output ("-" for stderr), optionally restricted to an
+    // address range or to the last instructions before an error
+    #[arg(
+        long,
+        value_name = "file|-",
+        help = "Log every executed instruction to a file or stderr"
+    )]
+    trace: Option<PathBuf>,
+    #[arg(long, value_name = "from-to", requires = "trace", value_parser = parse_address_range,
+          help = "Only trace instructions in this hex address range")]
+    trace_range: Option<RangeInclusive<u16>>,
+    #[arg(
+        long,
+        value_name = "n",
+        requires = "trace",
+        help = "Only log the last n instructions when an error occurs"
+    )]
+    trace_last: Option<usize>,
+    #[arg(long, help = "Print the hottest addresses and subroutines on exit")]
+    profile: bool,
+    #[arg(long, value_name = "file", help = "Write a callgrind profile on exit")]
+    callgrind: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "file",
+        help = "Write a ROM coverage report on exit, HTML for .html"
+    )]
+    coverage: Option<PathBuf>,
+    #[arg(long, help = "Only add a GIF frame when the screen changed")]
+    record_changes_only: bool,
+}
+
+#[derive(Args)]
+#[command(after_help = QUIRKS_HELP)]
+struct RunOptions {
+    #[command(flatten)]
+    emulator: Options,
+    #[arg(long, value_name = "ms", value_parser = parse_millis,
+          help = "Blend frames with the given persistence time constant")]
+    phosphor: Option<time::Duration>,
+    #[arg(long, help = "Flash a border while the sound timer is active")]
+    sound_indicator: bool,
+    #[arg(
+        long,
+        value_name = "keys",
+        help = "Keys for CHIP-8 keys 0-F, e.g. X,1,2,3,Q,W,E,A,S,D,Z,C,4,R,F,V"
+    )]
+    keymap: Option<Keymap>,
+    #[arg(
+        long = "gdb",
+        value_name = "port",
+        help = "Wait for a GDB connection on the given localhost port"
+    )]
+    gdb_port: Option<u16>,
+    #[arg(long, help = "Neither read nor update the config file")]
+    no_config: bool,
+}
+
+#[derive(Args)]
+#[command(after_help = QUIRKS_HELP)]
+struct HeadlessOptions {
+    #[command(flatten)]
+    emulator: Options,
+    #[arg(long, value_name = "n", help = "Number of frames to run")]
+    frames: u64,
+    #[arg(long, value_name = "file.png", help = "Save the final screen")]
+    screenshot: Option<PathBuf>,
+    #[arg(long, value_name = "file.gif", help = "Record an animated GIF")]
+    record: Option<PathBuf>,
+    #[arg(long, value_name = "file|-", help = "Dump raw RGB24 frames")]
+    raw_frames: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "file|-",
+        help = "Render the sound output to a WAV file"
+    )]
+    wav: Option<PathBuf>,
+}
+
+const QUIRKS_HELP: &str =
+    "Quirks: key-wait-on-press, shift-vy, load-store-i, jump-vx, logic-vf, clip";
+
+#[derive(Args)]
+struct LintOptions {
+    #[arg(value_name = "ROM")]
+    rom_path: String,
+    #[arg(
+        long,
+        help = "Also list instructions that depend on interpreter quirks"
+    )]
+    verbose: bool,
+    #[arg(
+        long,
+        value_name = "file.dot",
+        help = "Write the control-flow graph in Graphviz' DOT format"
+    )]
+    dot: Option<PathBuf>,
+}
+
+impl RunOptions {
+    // Takes what the command line leaves out from the config file's table for
+    // the ROM
+    fn fill_in(&mut self, rom: &RomConfig) {
+        let options = &mut self.emulator;
+        options.scale = options.scale.or(rom.scale);
+        options.palette = options.palette.or(rom.palette);
+        options.speed = options.speed.or(rom.speed);
+        options.volume = options.volume.or(rom.volume);
+        options.pitch = options.pitch.or(rom.pitch);
+        options.waveform = options.waveform.or(rom.waveform);
+        options.platform = options.platform.take().or_else(|| rom.platform.clone());
+        self.keymap = self.keymap.or(rom.keymap);
+    }
+}
+
+impl Options {
+    // The beeper settings, with the options left out taken from `default`
+    fn tone(&self, default: ToneSettings) -> ToneSettings {
+        ToneSettings {
+            pitch: self.pitch.unwrap_or(default.pitch),
+            volume: self.volume.unwrap_or(default.volume),
+            waveform: self.waveform.unwrap_or(default.waveform),
+            muted: self.mute,
+        }
     }
+}
+
+// Physical keys for the CHIP-8 keys 0 through F. Scancodes don't depend on
+// the keyboard layout.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Keymap([Scancode; chip8::NUM_KEYS]);
+
+impl Keymap {
+    fn key(&self, sc: Scancode) -> Option<Key> {
+        let index = self.0.iter().position(|&key| key == sc)?;
+        Key::new(index as u8)
+    }
+}
 
-    std::process::exit(match run(&args[1]) {
-        Ok(_) => 0,
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap([
+            Scancode::X,
+            Scancode::Num1,
+            Scancode::Num2,
+            Scancode::Num3,
+            Scancode::Q,
+            Scancode::W,
+            Scancode::E,
+            Scancode::A,
+            Scancode::S,
+            Scancode::D,
+            Scancode::Z,
+            Scancode::C,
+            Scancode::Num4,
+            Scancode::R,
+            Scancode::F,
+            Scancode::V,
+        ])
+    }
+}
+
+impl fmt::Display for Keymap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<&str> = self.0.iter().map(|key| key.name()).collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+// Parses 16 comma-separated SDL key names like "X,1,2,3,Q"
+impl FromStr for Keymap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let keys = s
+            .split(',')
+            .map(|name| Scancode::from_name(name.trim()).ok_or(format!("Unknown key: {}", name)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let keys = keys
+            .try_into()
+            .map_err(|_| format!("Expected {} keys", chip8::NUM_KEYS))?;
+        Ok(Keymap(keys))
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse_from(with_default_command(env::args().collect()));
+    match run(cli.command) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
         Err(err) => {
-            debug_eprintln!("ERROR: {:?}", err);
-            1
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
         }
-    });
+    }
+}
+
+// `chip-8 [OPTIONS] <ROM>` is short for `chip-8 run [OPTIONS] <ROM>`
+fn with_default_command(mut args: Vec<String>) -> Vec<String> {
+    let is_command = |arg: &str| {
+        arg == "help"
+            || ["-h", "--help", "-V", "--version"].contains(&arg)
+            || Cli::command().find_subcommand(arg).is_some()
+    };
+    if args.get(1).is_some_and(|arg| !is_command(arg)) {
+        args.insert(1, "run".to_string());
+    }
+    args
+}
+
+// Runs a command, Ok(false) means it failed after saying why
+fn run(command: Command) -> Result<bool, String> {
+    match command {
+        Command::Run(options) => run_sdl(options).map(|_| true),
+        Command::Headless(options) => headless::run(&options).map(|_| true),
+        Command::Disasm { rom_path } => disassemble(&rom_path).map(|_| true),
+        Command::Asm { source, output } => assemble(&source, output).map(|_| true),
+        Command::Info { rom_path } => info(&rom_path).map(|_| true),
+        Command::Lint(options) => lint(&options),
+    }
+}
+
+fn disassemble(rom_path: &Path) -> Result<(), String> {
+    let rom = fs::read(rom_path).map_err(|e| format!("Cannot read ROM: {}", e))?;
+    disasm::write_listing(BufWriter::new(io::stdout().lock()), &rom).map_err(|e| e.to_string())
+}
+
+fn assemble(source_path: &Path, output: Option<PathBuf>) -> Result<(), String> {
+    let source = fs::read_to_string(source_path)
+        .map_err(|e| format!("Cannot read {}: {}", source_path.display(), e))?;
+    let rom = asm::assemble(&source)?;
+    let output = output.unwrap_or_else(|| source_path.with_extension("ch8"));
+    fs::write(&output, &rom)
+        .map_err(|e| format!("Cannot write ROM {}: {}", output.display(), e))?;
+    println!("Assembled {} bytes to {}", rom.len(), output.display());
+    Ok(())
 }
 
-fn run(rom_path: &str) -> Result<(), String> {
+// Prints the issues found in the ROM, returns whether it is free of errors
+fn lint(options: &LintOptions) -> Result<bool, String> {
+    let rom = fs::read(&options.rom_path).map_err(|e| format!("Cannot read ROM: {}", e))?;
+    let analysis = analysis::analyze(&rom)?;
+    for issue in analysis.issues() {
+        if options.verbose || issue.severity != Severity::Info {
+            println!("{}", issue);
+        }
+    }
+
+    if let Some(path) = &options.dot {
+        let file = File::create(path)
+            .map_err(|e| format!("Cannot create graph {}: {}", path.display(), e))?;
+        analysis
+            .write_dot(BufWriter::new(file))
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(!analysis.has_errors())
+}
+
+// Prints the size and checksums of the ROM, what the ROM database knows about
+// it or which platform and quirks its code suggests, and statistics about the
+// code reachable from the entry point
+fn info(rom_path: &Path) -> Result<(), String> {
+    let rom = fs::read(rom_path).map_err(|e| format!("Cannot read ROM: {}", e))?;
+    let capacity = chip8::RAM_SIZE - chip8::PROGRAM_LOAD_ADDRESS;
+    let known = RomDatabase::bundled().lookup(&rom);
+    if let Some(info) = &known {
+        println!("Title:       {}", info.title);
+    }
+    if rom.len() <= capacity {
+        println!(
+            "Size:        {} bytes (fits, {} bytes free)",
+            rom.len(),
+            capacity - rom.len()
+        );
+    } else {
+        println!(
+            "Size:        {} bytes (too big, RAM holds {} bytes)",
+            rom.len(),
+            capacity
+        );
+    }
+    println!("SHA-1:       {}", romdb::sha1_hex(&rom));
+    println!("CRC-32:      {:08x}", crc32fast::hash(&rom));
+    // There is no code to look at in a ROM that can't be loaded
+    if rom.len() > capacity {
+        return Ok(());
+    }
+
+    let analysis = analysis::analyze(&rom)?;
+    let detection = match known {
+        Some(info) => {
+            println!("Platform:    {} (ROM database)", info.platform);
+            println!("Quirks:      {}", info.quirks);
+            None
+        }
+        None => {
+            let detection = detect::detect(&analysis);
+            println!("Platform:    {} (detected)", detection.platform);
+            println!("Quirks:      {}", detection.quirks);
+            Some(detection)
+        }
+    };
+
+    match analysis.max_call_depth() {
+        Some(depth) => println!("Call depth:  {}", depth),
+        None => println!("Call depth:  unbounded, subroutines recurse"),
+    }
+    let histogram = analysis.opcode_histogram();
+    let opcodes: Vec<&str> = histogram.keys().copied().collect();
+    println!("Opcodes:     {}", opcodes.join(" "));
+
+    println!(
+        "\nInstructions ({} reachable):",
+        histogram.values().sum::<usize>()
+    );
+    let mut counts: Vec<_> = histogram.into_iter().collect();
+    counts.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+    for (class, count) in counts {
+        println!("{:>8}  {}", count, class);
+    }
+
+    if let Some(detection) = detection.filter(|d| !d.evidence.is_empty()) {
+        println!("\nPlatform evidence:");
+        for evidence in &detection.evidence {
+            println!("  {}", evidence);
+        }
+    }
+    Ok(())
+}
+
+// Parses hex address ranges like "200-2ff"
+fn parse_address_range(arg: &str) -> Result<RangeInclusive<u16>, String> {
+    let address = |s: &str| {
+        u16::from_str_radix(s.trim_start_matches("0x"), 16)
+            .map_err(|_| format!("Invalid address: {}", s))
+    };
+    let (from, to) = arg.split_once('-').ok_or("expected <from>-<to>")?;
+    Ok(address(from)?..=address(to)?)
+}
+
+fn parse_millis(arg: &str) -> Result<time::Duration, String> {
+    let ms = arg
+        .parse()
+        .map_err(|_| format!("Invalid duration: {}", arg))?;
+    Ok(time::Duration::from_millis(ms))
+}
+
+fn parse_pitch(arg: &str) -> Result<f32, String> {
+    arg.parse()
+        .ok()
+        .filter(|hz| (MIN_PITCH..=MAX_PITCH).contains(hz))
+        .ok_or(format!(
+            "Invalid frequency: {} (expected {}-{} Hz)",
+            arg, MIN_PITCH, MAX_PITCH
+        ))
+}
+
+fn parse_volume(arg: &str) -> Result<f32, String> {
+    arg.parse()
+        .ok()
+        .filter(|v| (0.0..=1.0).contains(v))
+        .ok_or(format!("Volume must be between 0 and 1: {}", arg))
+}
+
+// How to run the loaded ROM: what the ROM database recommends, unless the
+// command line says otherwise
+struct RomSettings {
+    title: Option<String>,
+    ticks_per_frame: usize,
+    palette: Palette,
+    // CHIP-8 keys for the arrow keys, space ("a") and left shift ("b")
+    keys: BTreeMap<String, Key>,
+}
+
+// Settings come from the options, then the ROM database, then the config file
+fn setup_chip8(
+    chip8: &mut chip8::Chip8,
+    options: &Options,
+    config: &Config,
+) -> Result<RomSettings, String> {
+    if let Some(path) = &options.trace {
+        let output: Box<dyn Write> = if path == Path::new("-") {
+            Box::new(io::stderr())
+        } else {
+            let file = File::create(path)
+                .map_err(|e| format!("Cannot create trace {}: {}", path.display(), e))?;
+            Box::new(BufWriter::new(file))
+        };
+        chip8.set_tracer(Tracer::new(
+            output,
+            options.trace_range.clone(),
+            options.trace_last,
+        ));
+    }
+    if options.profile || options.callgrind.is_some() {
+        chip8.set_profiler(Profiler::new());
+    }
+    if options.coverage.is_some() {
+        chip8.set_coverage(Coverage::new());
+    }
+    if let Some(seed) = options.seed {
+        chip8.set_seed(seed);
+    }
+    load_rom(chip8, &options.rom_path)?;
+
+    let database = RomDatabase::bundled();
+    let info = if options.no_rom_db {
+        None
+    } else {
+        database.lookup(chip8.rom())
+    };
+    if let Some(info) = &info {
+        debug_eprintln!("ROM database: {} ({})", info.title, info.platform);
+    }
+
+    let platform_quirks = |platform: &str| {
+        database
+            .platform_quirks(platform)
+            .ok_or_else(|| format!("Unknown platform: {}", platform))
+    };
+    let mut quirks = match (&options.platform, &info, &config.platform) {
+        (Some(platform), _, _) => platform_quirks(platform)?,
+        (None, Some(info), _) => info.quirks,
+        (None, None, Some(platform)) => platform_quirks(platform)?,
+        (None, None, None) => Quirks::default(),
+    };
+    for (name, enabled) in &options.quirks {
+        quirks.set(name, *enabled)?;
+    }
+    chip8.set_quirks(quirks);
+
+    let info = info.as_ref();
+    Ok(RomSettings {
+        title: info.map(|info| info.title.clone()),
+        ticks_per_frame: options
+            .speed
+            .or(info.and_then(|info| info.tick_rate))
+            .or(config.speed)
+            .unwrap_or(chip8::TICKS_PER_FRAME),
+        palette: options
+            .palette
+            .or(info.and_then(|info| info.palette))
+            .or(config.palette)
+            .unwrap_or_default(),
+        keys: info.map(|info| info.keys.clone()).unwrap_or_default(),
+    })
+}
+
+// Writes out what setup_chip8 asked to collect while the ROM ran
+fn finish_chip8(chip8: &chip8::Chip8, options: &Options) -> Result<(), String> {
+    if let (Some(coverage), Some(path)) = (chip8.coverage(), &options.coverage) {
+        let file = File::create(path)
+            .map_err(|e| format!("Cannot create coverage report {}: {}", path.display(), e))?;
+        let writer = BufWriter::new(file);
+        let written = if path.extension().is_some_and(|ext| ext == "html") {
+            coverage.write_html(writer, chip8.rom())
+        } else {
+            coverage.write_text(writer, chip8.rom())
+        };
+        written.map_err(|e| e.to_string())?;
+        debug_eprintln!("{}", coverage.summary(chip8.rom().len()));
+    }
+
+    let Some(profiler) = chip8.profiler() else {
+        return Ok(());
+    };
+    if options.profile {
+        profiler
+            .write_report(io::stderr())
+            .map_err(|e| e.to_string())?;
+    }
+    if let Some(path) = &options.callgrind {
+        let file = File::create(path)
+            .map_err(|e| format!("Cannot create profile {}: {}", path.display(), e))?;
+        profiler
+            .write_callgrind(BufWriter::new(file))
+            .map_err(|e| e.to_string())?;
+        debug_eprintln!("Saved profile to {}", path.display());
+    }
+    Ok(())
+}
+
+fn load_rom(chip8: &mut chip8::Chip8, rom_path: &str) -> Result<(), String> {
+    debug_eprint!("Loading ROM: {}: ", rom_path);
+    let rom = fs::read(rom_path).map_err(|e| format!("Cannot read ROM: {}", e))?;
+    let byte_count = chip8.load_rom(rom)?;
+    debug_eprintln!("Done ({} bytes)", byte_count);
+    Ok(())
+}
+
+fn run_sdl(mut run: RunOptions) -> Result<(), String> {
+    let config_path = config::path().filter(|_| !run.no_config);
+    let mut config = match &config_path {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    // A ROM that can't be read gets reported when it is loaded
+    if let Some(rom_config) = fs::read(&run.emulator.rom_path)
+        .ok()
+        .and_then(|rom| config.rom(&rom))
+    {
+        run.fill_in(rom_config);
+    }
+    let run = &run;
+    let options = &run.emulator;
+    let scale = options.scale.or(config.scale).unwrap_or(DEFAULT_SCALE);
+    let keymap = run.keymap.or(config.keymap).unwrap_or_default();
+    let tone = options.tone(config.tone());
+
     debug_print!("Initializing SDL: ");
     let sdl_context = sdl2::init()?;
     let video_subsystem = sdl_context.video()?;
     let audio_subsystem = sdl_context.audio()?;
     debug_println!("Done");
 
-    let speaker = sdl_speaker::SDLSpeaker::new(&audio_subsystem);
-    let mut chip8 = chip8::Chip8::new(Box::new(speaker));
+    let speaker = sdl_speaker::SDLSpeaker::new(&audio_subsystem, tone);
+    let mut chip8 = chip8::Chip8::new(Box::new(speaker.clone()));
 
-    debug_print!("Loading ROM: {}: ", rom_path);
-    let rom = fs::read(rom_path).map_err(|e| format!("Cannot read ROM: {}", e))?;
-    let byte_count = chip8.load_rom(rom)?;
-    debug_println!("Done ({} bytes)", byte_count);
+    let settings = setup_chip8(&mut chip8, options, &config)?;
+    let palette = settings.palette;
+
+    // Connect the debugger before there is a window that can't respond
+    // while waiting
+    let mut gdb = match run.gdb_port {
+        Some(port) => {
+            println!("Waiting for GDB to connect on localhost:{}", port);
+            Some(GdbStub::listen(port)?)
+        }
+        None => None,
+    };
 
+    let title = match &settings.title {
+        Some(title) => format!("{} - {}", WINDOW_TITLE, title),
+        None => WINDOW_TITLE.to_string(),
+    };
     let window = video_subsystem
-        .window(WINDOW_TITLE, WINDOW_WIDTH, WINDOW_HEIGHT)
+        .window(
+            &title,
+            chip8::DISPLAY_WIDTH as u32 * scale,
+            chip8::DISPLAY_HEIGHT as u32 * scale,
+        )
         .position_centered()
         .build()
         .expect("Could not initialize video subsystem");
@@ -62,6 +695,21 @@ fn run(rom_path: &str) -> Result<(), String> {
 
     canvas.present();
 
+    // The whole CHIP-8 screen lives in a single texture that gets scaled up
+    // to the window size when copied to the canvas
+    let texture_creator = canvas.texture_creator();
+    let mut texture = texture_creator
+        .create_texture_streaming(
+            PixelFormatEnum::RGB24,
+            chip8::DISPLAY_WIDTH as u32,
+            chip8::DISPLAY_HEIGHT as u32,
+        )
+        .map_err(|e| e.to_string())?;
+
+    let mut phosphor = run.phosphor.map(PhosphorFilter::new);
+    let mut recorder: Option<GifRecorder<BufWriter<File>>> = None;
+    let mut sound_indicator = run.sound_indicator;
+
     let mut event_pump = sdl_context.event_pump()?;
 
     'mainloop: loop {
@@ -76,30 +724,110 @@ fn run(rom_path: &str) -> Result<(), String> {
                     break 'mainloop;
                 }
                 Event::KeyDown {
-                    scancode: Some(sc), ..
+                    scancode: Some(Scancode::F12),
+                    ..
                 } => {
-                    if let Some(key) = map_scancode_to_key(sc) {
-                        debug_println!("key down: {}", key);
-                        chip8.key_down(key);
+                    // A failed screenshot shouldn't end the game
+                    let path = screenshot::timestamped_path(Path::new("."), "png");
+                    match chip8.save_screenshot(&path, scale as usize, &palette) {
+                        Ok(()) => {
+                            debug_println!("Saved screenshot to {}", path.display());
+                        }
+                        Err(err) => eprintln!("error: {}", err),
                     }
                 }
+                Event::KeyDown {
+                    scancode: Some(Scancode::F10),
+                    ..
+                } => match recorder.take() {
+                    Some(gif) => match gif.finish() {
+                        Ok(_) => {
+                            debug_println!("Recording stopped");
+                        }
+                        Err(err) => eprintln!("error: {}", err),
+                    },
+                    None => {
+                        let path = screenshot::timestamped_path(Path::new("."), "gif");
+                        match create_gif_recorder(&path, &palette, scale, options) {
+                            Ok(gif) => {
+                                recorder = Some(gif);
+                                debug_println!("Recording to {}", path.display());
+                            }
+                            Err(err) => eprintln!("error: {}", err),
+                        }
+                    }
+                },
+                // Keys the keymap uses take precedence over the hotkeys, the
+                // same way for presses and releases
+                Event::KeyDown {
+                    scancode: Some(sc),
+                    repeat,
+                    timestamp,
+                    ..
+                } => match map_key(sc, &keymap, &settings) {
+                    Some(key) => {
+                        if !repeat {
+                            debug_println!("key down: {}", key);
+                            chip8.queue_key_event(chip8::KeyEvent {
+                                key,
+                                pressed: true,
+                                timestamp: timestamp.into(),
+                            });
+                        }
+                    }
+                    None if sc == Scancode::I => sound_indicator = !sound_indicator,
+                    None => {
+                        if let Some(change) = tone_hotkey(sc) {
+                            speaker.update_settings(change);
+                            debug_println!("tone: {:?}", speaker.settings());
+                        }
+                    }
+                },
                 Event::KeyUp {
-                    scancode: Some(sc), ..
+                    scancode: Some(sc),
+                    timestamp,
+                    ..
                 } => {
-                    if let Some(key) = map_scancode_to_key(sc) {
+                    if let Some(key) = map_key(sc, &keymap, &settings) {
                         debug_println!("key up: {}", key);
-                        chip8.key_up(key);
+                        chip8.queue_key_event(chip8::KeyEvent {
+                            key,
+                            pressed: false,
+                            timestamp: timestamp.into(),
+                        });
                     }
                 }
                 _ => {}
             }
         }
 
-        for _ in 0..chip8::TICKS_PER_FRAME {
-            chip8.exec();
+        match gdb.as_mut() {
+            Some(stub) => match stub.run_frame(&mut chip8, settings.ticks_per_frame)? {
+                Session::Attached => {}
+                Session::Detached => {
+                    debug_println!("GDB detached");
+                    gdb = None;
+                }
+                Session::Killed => break 'mainloop,
+            },
+            None => chip8.run_frame(settings.ticks_per_frame),
         }
-        chip8.update_timers();
-        draw_frame(&chip8, &mut canvas);
+        // Recording errors stop the recording, not the game
+        if let Some(gif) = recorder.as_mut()
+            && let Err(err) = gif.capture(chip8.screen(), TARGET_FRAME_TIME)
+        {
+            eprintln!("error: {}", err);
+            recorder = None;
+        }
+        let show_sound_indicator = sound_indicator && chip8.is_sound_active();
+        draw_frame(
+            &mut chip8,
+            phosphor.as_mut(),
+            &palette,
+            show_sound_indicator,
+            &mut canvas,
+            &mut texture,
+        )?;
 
         let frame_time = frame_start.elapsed();
         if frame_time < TARGET_FRAME_TIME {
@@ -107,63 +835,367 @@ fn run(rom_path: &str) -> Result<(), String> {
         }
     }
 
-    Ok(())
+    if let Some(gif) = recorder {
+        gif.finish()?;
+    }
+
+    let finished = finish_chip8(&chip8, options);
+
+    // Losing the recent ROMs or the tone isn't worth failing the run for
+    if let Some(path) = &config_path {
+        config.add_recent(Path::new(&options.rom_path));
+        config.remember_tone_changes(tone, speaker.settings());
+        if let Err(err) = config.save(path) {
+            eprintln!("warning: {}", err);
+        }
+    }
+    finished
+}
+
+fn create_gif_recorder(
+    path: &Path,
+    palette: &Palette,
+    scale: u32,
+    options: &Options,
+) -> Result<GifRecorder<BufWriter<File>>, String> {
+    let file = File::create(path)
+        .map_err(|e| format!("Cannot create recording {}: {}", path.display(), e))?;
+    GifRecorder::new(
+        BufWriter::new(file),
+        scale as usize,
+        palette,
+        options.record_changes_only,
+    )
+}
+
+fn map_key(sc: Scancode, keymap: &Keymap, settings: &RomSettings) -> Option<Key> {
+    keymap
+        .key(sc)
+        .or_else(|| map_control_to_key(sc, &settings.keys))
+}
+
+// Controls the ROM database can map to keys, for ROMs played with the arrow
+// keys and an action button or two
+fn map_control_to_key(sc: Scancode, keys: &BTreeMap<String, Key>) -> Option<Key> {
+    let control = match sc {
+        Scancode::Up => "up",
+        Scancode::Down => "down",
+        Scancode::Left => "left",
+        Scancode::Right => "right",
+        Scancode::Space => "a",
+        Scancode::LShift => "b",
+        _ => return None,
+    };
+    keys.get(control).copied()
 }
 
-fn map_scancode_to_key(sc: Scancode) -> Option<usize> {
+// Hotkeys for adjusting the beeper while the emulator is running
+fn tone_hotkey(sc: Scancode) -> Option<fn(&mut ToneSettings)> {
     match sc {
-        Scancode::Num1 => Some(1),
-        Scancode::Num2 => Some(2),
-        Scancode::Num3 => Some(3),
-        Scancode::Num4 => Some(12),
-        Scancode::Q => Some(4),
-        Scancode::W => Some(5),
-        Scancode::E => Some(6),
-        Scancode::R => Some(13),
-        Scancode::A => Some(7),
-        Scancode::S => Some(8),
-        Scancode::D => Some(9),
-        Scancode::F => Some(14),
-        Scancode::Z => Some(10),
-        Scancode::X => Some(0),
-        Scancode::C => Some(11),
-        Scancode::V => Some(15),
+        Scancode::M => Some(|tone| tone.muted = !tone.muted),
+        Scancode::Minus => Some(|tone| tone.volume = (tone.volume - VOLUME_STEP).max(0.0)),
+        Scancode::Equals => Some(|tone| tone.volume = (tone.volume + VOLUME_STEP).min(1.0)),
+        Scancode::LeftBracket => Some(|tone| tone.pitch = (tone.pitch / SEMITONE).max(MIN_PITCH)),
+        Scancode::RightBracket => Some(|tone| tone.pitch = (tone.pitch * SEMITONE).min(MAX_PITCH)),
+        Scancode::N => Some(|tone| tone.waveform = tone.waveform.next()),
         _ => None,
     }
 }
 
-fn draw_frame(chip8: &chip8::Chip8, canvas: &mut sdl2::render::Canvas<sdl2::video::Window>) {
-    // Clear the canvas to black before rendering each frame
-    canvas.set_draw_color(Color::RGB(0, 0, 0));
-    canvas.clear();
-
-    // Render only the pixels that are set
-    for y in 0..chip8::DISPLAY_HEIGHT {
-        for x in 0..chip8::DISPLAY_WIDTH {
-            if chip8.is_pixel_set(x, y) {
-                canvas.set_draw_color(Color::RGB(255, 255, 255));
-                canvas
-                    .fill_rect(Rect::new(
-                        (x as u32 * SCALE_FACTOR) as i32,
-                        (y as u32 * SCALE_FACTOR) as i32,
-                        SCALE_FACTOR,
-                        SCALE_FACTOR,
-                    ))
-                    .unwrap();
-            }
+fn draw_frame(
+    chip8: &mut chip8::Chip8,
+    phosphor: Option<&mut PhosphorFilter>,
+    palette: &Palette,
+    show_sound_indicator: bool,
+    canvas: &mut Canvas<Window>,
+    texture: &mut Texture,
+) -> Result<(), String> {
+    // Only upload the framebuffer when the emulator touched the screen or
+    // pixels are still fading out
+    let dirty = chip8.take_screen_dirty();
+    match phosphor {
+        Some(filter) if dirty || !filter.is_settled() => {
+            let intensity = filter.update(chip8.screen(), TARGET_FRAME_TIME);
+            upload_pixels(texture, intensity.iter().map(|&i| palette.blend(i)))?;
+        }
+        None if dirty => {
+            upload_pixels(texture, chip8.screen().iter().map(|&p| palette.color(p)))?;
         }
+        _ => {}
+    }
+
+    canvas.copy(texture, None, None)?;
+    if show_sound_indicator {
+        // Half a CHIP-8 pixel wide, whatever the scale
+        let (width, _) = canvas.output_size()?;
+        let border = (width / chip8::DISPLAY_WIDTH as u32 / 2).max(1);
+        draw_border(canvas, SOUND_INDICATOR_COLOR, border)?;
     }
     canvas.present();
+    Ok(())
+}
+
+fn draw_border(canvas: &mut Canvas<Window>, color: Color, width: u32) -> Result<(), String> {
+    let (w, h) = canvas.output_size()?;
+    canvas.set_draw_color(color);
+    canvas.fill_rects(&[
+        Rect::new(0, 0, w, width),
+        Rect::new(0, (h - width) as i32, w, width),
+        Rect::new(0, 0, width, h),
+        Rect::new((w - width) as i32, 0, width, h),
+    ])
+}
+
+fn upload_pixels(
+    texture: &mut Texture,
+    pixels: impl Iterator<Item = [u8; 3]>,
+) -> Result<(), String> {
+    texture.with_lock(None, |buffer: &mut [u8], pitch: usize| {
+        for (i, color) in pixels.enumerate() {
+            let offset = (i / chip8::DISPLAY_WIDTH) * pitch + (i % chip8::DISPLAY_WIDTH) * 3;
+            buffer[offset..offset + 3].copy_from_slice(&color);
+        }
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn parse(args: &[&str]) -> Result<Command, clap::Error> {
+        let args = ["chip-8"].iter().chain(args).map(|arg| arg.to_string());
+        Cli::try_parse_from(with_default_command(args.collect())).map(|cli| cli.command)
+    }
+
+    fn parse_run(args: &[&str]) -> RunOptions {
+        match parse(args) {
+            Ok(Command::Run(options)) => options,
+            _ => panic!("not a run command: {:?}", args),
+        }
+    }
+
     #[test]
     fn it_maps_physical_keys_to_virtual_ones() {
-        assert_eq!(map_scancode_to_key(Scancode::A), Some(7));
-        assert_eq!(map_scancode_to_key(Scancode::X), Some(0));
-        assert_eq!(map_scancode_to_key(Scancode::M), None);
+        let keymap = Keymap::default();
+        assert_eq!(keymap.key(Scancode::A), Key::new(7));
+        assert_eq!(keymap.key(Scancode::X), Key::new(0));
+        assert_eq!(keymap.key(Scancode::M), None);
+
+        let keymap: Keymap = "0,1,2,3,4,5,6,7,8,9,A,B,C,D,E,F".parse().unwrap();
+        assert_eq!(keymap.key(Scancode::A), Key::new(0xA));
+        assert_eq!(keymap.to_string(), "0,1,2,3,4,5,6,7,8,9,A,B,C,D,E,F");
+        assert_eq!(keymap.key(Scancode::Num0), Key::new(0));
+        assert!("X,1,2".parse::<Keymap>().is_err());
+        assert!(
+            "0,1,2,3,4,5,6,7,8,9,A,B,C,D,E,Nope"
+                .parse::<Keymap>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn it_maps_controls_from_the_rom_database() {
+        let keys = BTreeMap::from([("up".to_string(), Key::new(5).unwrap())]);
+        assert_eq!(map_control_to_key(Scancode::Up, &keys), Key::new(5));
+        assert_eq!(map_control_to_key(Scancode::Down, &keys), None);
+        assert_eq!(map_control_to_key(Scancode::W, &keys), None);
+    }
+
+    #[test]
+    fn it_runs_roms_without_a_subcommand() {
+        let options = parse_run(&["--phosphor", "40", "game.ch8"]);
+        assert_eq!(options.emulator.rom_path, "game.ch8");
+        assert_eq!(options.phosphor, Some(time::Duration::from_millis(40)));
+        assert_eq!(options.emulator.scale, None);
+
+        let options = parse_run(&["run", "--scale", "4", "game.ch8"]);
+        assert!(options.phosphor.is_none());
+        assert_eq!(options.emulator.scale, Some(4));
+
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["--phosphor", "x", "game.ch8"]).is_err());
+        assert!(parse(&["--scale", "0", "game.ch8"]).is_err());
+        assert!(matches!(
+            parse(&["info", "game.ch8"]),
+            Ok(Command::Info { .. })
+        ));
+    }
+
+    #[test]
+    fn it_only_accepts_output_options_in_headless_mode() {
+        let Ok(Command::Headless(options)) = parse(&[
+            "headless",
+            "--frames",
+            "60",
+            "--screenshot",
+            "out.png",
+            "game.ch8",
+        ]) else {
+            panic!("not a headless command");
+        };
+        assert_eq!(options.frames, 60);
+        assert_eq!(options.screenshot, Some(PathBuf::from("out.png")));
+
+        assert!(parse(&["--screenshot", "out.png", "game.ch8"]).is_err());
+        assert!(parse(&["--record", "out.gif", "game.ch8"]).is_err());
+        assert!(parse(&["headless", "--frames", "60", "--gdb", "1234", "game.ch8"]).is_err());
+        assert!(parse(&["headless", "game.ch8"]).is_err());
+    }
+
+    #[test]
+    fn it_parses_trace_options() {
+        let options = parse_run(&[
+            "--trace",
+            "-",
+            "--trace-range",
+            "200-0x2FF",
+            "--trace-last",
+            "100",
+            "game.ch8",
+        ])
+        .emulator;
+        assert_eq!(options.trace, Some(PathBuf::from("-")));
+        assert_eq!(options.trace_range, Some(0x200..=0x2FF));
+        assert_eq!(options.trace_last, Some(100));
+
+        assert!(parse(&["--trace-last", "100", "game.ch8"]).is_err());
+        assert!(parse(&["--trace", "-", "--trace-range", "200", "game.ch8"]).is_err());
+    }
+
+    #[test]
+    fn it_parses_rom_setting_overrides() {
+        let options = parse_run(&[
+            "--quirk",
+            "clip",
+            "--quirk",
+            "shift-vy=off",
+            "--quirk",
+            "key-wait-on-press",
+            "--speed",
+            "20",
+            "--palette",
+            "#102030,f0e0d0",
+            "--no-rom-db",
+            "--seed",
+            "42",
+            "game.ch8",
+        ])
+        .emulator;
+        assert_eq!(
+            options.quirks,
+            [
+                ("clip".to_string(), true),
+                ("shift-vy".to_string(), false),
+                ("key-wait-on-press".to_string(), true)
+            ]
+        );
+        assert_eq!(options.speed, Some(20));
+        assert_eq!(
+            options.palette,
+            Some(Palette::new([0x10, 0x20, 0x30], [0xf0, 0xe0, 0xd0]))
+        );
+        assert!(options.no_rom_db);
+        assert_eq!(options.seed, Some(42));
+        assert_eq!(
+            parse_run(&["--ticks", "5", "game.ch8"]).emulator.speed,
+            Some(5)
+        );
+
+        assert!(parse(&["--quirk", "vblank", "game.ch8"]).is_err());
+        assert!(parse(&["--quirk", "clip=yes", "game.ch8"]).is_err());
+        assert!(parse(&["--speed", "0", "game.ch8"]).is_err());
+        assert!(parse(&["--palette", "000000", "game.ch8"]).is_err());
+
+        let names = chip_8::quirks::QUIRK_NAMES.join(", ");
+        assert_eq!(QUIRKS_HELP, format!("Quirks: {}", names));
+    }
+
+    #[test]
+    fn it_parses_lint_options() {
+        let Ok(Command::Lint(options)) = parse(&["lint", "--dot", "cfg.dot", "game.ch8"]) else {
+            panic!("not a lint command");
+        };
+        assert_eq!(options.rom_path, "game.ch8");
+        assert_eq!(options.dot, Some(PathBuf::from("cfg.dot")));
+        assert!(!options.verbose);
+
+        assert!(parse(&["lint", "--dot"]).is_err());
+        assert!(parse(&["lint", "a.ch8", "b.ch8"]).is_err());
+    }
+
+    #[test]
+    fn it_parses_tone_settings() {
+        let options = parse_run(&[
+            "--pitch",
+            "880",
+            "--volume",
+            "0.5",
+            "--waveform",
+            "sine",
+            "--mute",
+            "game.ch8",
+        ]);
+        assert_eq!(
+            options.emulator.tone(ToneSettings::default()),
+            ToneSettings {
+                pitch: 880.0,
+                volume: 0.5,
+                waveform: Waveform::Sine,
+                muted: true,
+            }
+        );
+
+        // Options left out come from the config file's tone
+        let defaults = ToneSettings {
+            volume: 0.2,
+            ..ToneSettings::default()
+        };
+        let options = parse_run(&["--pitch", "880", "--no-config", "game.ch8"]);
+        assert_eq!(options.emulator.tone(defaults).volume, 0.2);
+        assert!(options.no_config);
+
+        assert!(parse_run(&["--sound-indicator", "game.ch8"]).sound_indicator);
+        assert!(parse(&["--volume", "2", "game.ch8"]).is_err());
+        assert!(parse(&["--pitch", "0", "game.ch8"]).is_err());
+        assert!(parse(&["--waveform", "saw", "game.ch8"]).is_err());
+    }
+
+    #[test]
+    fn tone_hotkeys_adjust_the_settings() {
+        let mut tone = ToneSettings::default();
+        tone_hotkey(Scancode::M).unwrap()(&mut tone);
+        tone_hotkey(Scancode::N).unwrap()(&mut tone);
+        assert!(tone.muted);
+        assert_eq!(tone.waveform, Waveform::Sine);
+
+        tone.volume = 1.0;
+        tone_hotkey(Scancode::Equals).unwrap()(&mut tone);
+        assert_eq!(tone.volume, 1.0);
+
+        // Holding the pitch keys stops at the ends of the audible range
+        for _ in 0..200 {
+            tone_hotkey(Scancode::RightBracket).unwrap()(&mut tone);
+        }
+        assert_eq!(tone.pitch, MAX_PITCH);
+        for _ in 0..300 {
+            tone_hotkey(Scancode::LeftBracket).unwrap()(&mut tone);
+        }
+        assert_eq!(tone.pitch, MIN_PITCH);
+
+        // Keypad keys are not hotkeys
+        assert!(tone_hotkey(Scancode::Q).is_none());
+    }
+
+    #[test]
+    fn only_tone_hotkey_changes_are_remembered() {
+        let mut config = Config::parse("pitch = 440.0\nvolume = 0.5").unwrap();
+        let options = parse_run(&["--pitch", "880", "game.ch8"]);
+        let start = options.emulator.tone(config.tone());
+        let mut end = start;
+        tone_hotkey(Scancode::Equals).unwrap()(&mut end);
+
+        config.remember_tone_changes(start, end);
+        assert_eq!(config.pitch, Some(440.0));
+        assert_eq!(config.volume, Some(0.5 + VOLUME_STEP));
     }
 }
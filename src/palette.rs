@@ -0,0 +1,110 @@
+use std::fmt;
+use std::str::FromStr;
+
+// Colors used to render unlit and lit pixels, as RGB triples
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Palette {
+    pub background: [u8; 3],
+    pub foreground: [u8; 3],
+}
+
+impl Palette {
+    pub const fn new(background: [u8; 3], foreground: [u8; 3]) -> Self {
+        Palette {
+            background,
+            foreground,
+        }
+    }
+
+    pub fn color(&self, lit: bool) -> [u8; 3] {
+        if lit {
+            self.foreground
+        } else {
+            self.background
+        }
+    }
+
+    // Linear blend between background and foreground for intensities in 0..=1
+    pub fn blend(&self, intensity: f32) -> [u8; 3] {
+        let intensity = intensity.clamp(0.0, 1.0);
+        let mut color = [0; 3];
+        for (c, (&bg, &fg)) in color
+            .iter_mut()
+            .zip(self.background.iter().zip(&self.foreground))
+        {
+            *c = (bg as f32 + (fg as f32 - bg as f32) * intensity).round() as u8;
+        }
+        color
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::new([0x00, 0x00, 0x00], [0xff, 0xff, 0xff])
+    }
+}
+
+// Parses "background,foreground" hex colors, like "000000,ffffff"
+impl FromStr for Palette {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let color = |hex| parse_color(hex).ok_or(format!("Invalid color: {}", hex));
+        let (background, foreground) = s
+            .split_once(',')
+            .ok_or("expected two colors separated by a comma")?;
+        Ok(Palette::new(color(background)?, color(foreground)?))
+    }
+}
+
+impl fmt::Display for Palette {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [r, g, b] = self.background;
+        write!(f, "{:02x}{:02x}{:02x},", r, g, b)?;
+        let [r, g, b] = self.foreground;
+        write!(f, "{:02x}{:02x}{:02x}", r, g, b)
+    }
+}
+
+// Parses colors written as "#rrggbb" or "rrggbb"
+pub fn parse_color(hex: &str) -> Option<[u8; 3]> {
+    let hex = hex.strip_prefix('#').unwrap_or(hex);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_blends_between_background_and_foreground() {
+        let palette = Palette::new([0, 0, 100], [200, 100, 0]);
+        assert_eq!(palette.blend(0.0), palette.background);
+        assert_eq!(palette.blend(1.0), palette.foreground);
+        assert_eq!(palette.blend(0.5), [100, 50, 50]);
+    }
+
+    #[test]
+    fn it_parses_hex_colors() {
+        assert_eq!(parse_color("#ff8000"), Some([0xff, 0x80, 0x00]));
+        assert_eq!(parse_color("0a0B0c"), Some([0x0a, 0x0b, 0x0c]));
+        assert_eq!(parse_color("#fff"), None);
+        assert_eq!(parse_color("gg0000"), None);
+    }
+
+    #[test]
+    fn it_parses_and_prints_palettes() {
+        let palette: Palette = "#102030,f0e0d0".parse().unwrap();
+        assert_eq!(
+            palette,
+            Palette::new([0x10, 0x20, 0x30], [0xf0, 0xe0, 0xd0])
+        );
+        assert_eq!(palette.to_string(), "102030,f0e0d0");
+        assert!("000000".parse::<Palette>().is_err());
+        assert!("000000,fff".parse::<Palette>().is_err());
+    }
+}
@@ -0,0 +1,114 @@
+use std::time::Duration;
+
+use crate::chip8::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+
+pub const DEFAULT_TIME_CONSTANT: Duration = Duration::from_millis(30);
+
+// Intensities below this are indistinguishable from black in 8-bit output
+const CUTOFF: f32 = 1.0 / 255.0;
+
+// Emulates the afterglow of a phosphor CRT: lit pixels are at full intensity,
+// unlit ones decay exponentially with the given time constant. This smooths
+// out the flicker caused by games erasing and redrawing sprites with XOR.
+pub struct PhosphorFilter {
+    time_constant: Duration,
+    intensity: [f32; DISPLAY_WIDTH * DISPLAY_HEIGHT],
+}
+
+impl PhosphorFilter {
+    pub fn new(time_constant: Duration) -> Self {
+        PhosphorFilter {
+            time_constant,
+            intensity: [0.0; DISPLAY_WIDTH * DISPLAY_HEIGHT],
+        }
+    }
+
+    pub fn time_constant(&self) -> Duration {
+        self.time_constant
+    }
+
+    // Advances the filter by `elapsed` and blends in the current screen
+    pub fn update(&mut self, screen: &[bool], elapsed: Duration) -> &[f32] {
+        let decay = if self.time_constant.is_zero() {
+            0.0
+        } else {
+            (-elapsed.as_secs_f32() / self.time_constant.as_secs_f32()).exp()
+        };
+
+        for (intensity, &lit) in self.intensity.iter_mut().zip(screen) {
+            *intensity = if lit {
+                1.0
+            } else if *intensity * decay < CUTOFF {
+                0.0
+            } else {
+                *intensity * decay
+            };
+        }
+        &self.intensity
+    }
+
+    pub fn intensity(&self) -> &[f32] {
+        &self.intensity
+    }
+
+    // True when no pixel is fading, i.e. further updates with an unchanged
+    // screen won't change the output
+    pub fn is_settled(&self) -> bool {
+        self.intensity.iter().all(|&i| i == 0.0 || i == 1.0)
+    }
+}
+
+impl Default for PhosphorFilter {
+    fn default() -> Self {
+        PhosphorFilter::new(DEFAULT_TIME_CONSTANT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: Duration = Duration::from_millis(16);
+
+    fn screen_with(lit: &[usize]) -> Vec<bool> {
+        let mut screen = vec![false; DISPLAY_WIDTH * DISPLAY_HEIGHT];
+        for &i in lit {
+            screen[i] = true;
+        }
+        screen
+    }
+
+    #[test]
+    fn lit_pixels_are_at_full_intensity() {
+        let mut filter = PhosphorFilter::default();
+        let intensity = filter.update(&screen_with(&[0, 5]), FRAME);
+        assert_eq!(intensity[0], 1.0);
+        assert_eq!(intensity[5], 1.0);
+        assert_eq!(intensity[1], 0.0);
+    }
+
+    #[test]
+    fn unlit_pixels_decay_over_time() {
+        let mut filter = PhosphorFilter::new(Duration::from_millis(100));
+        filter.update(&screen_with(&[0]), FRAME);
+
+        let first = filter.update(&screen_with(&[]), FRAME)[0];
+        let second = filter.update(&screen_with(&[]), FRAME)[0];
+        assert!(first < 1.0 && first > 0.0);
+        assert!(second < first && second > 0.0);
+        assert!(!filter.is_settled());
+
+        for _ in 0..100 {
+            filter.update(&screen_with(&[]), FRAME);
+        }
+        assert_eq!(filter.intensity()[0], 0.0);
+        assert!(filter.is_settled());
+    }
+
+    #[test]
+    fn a_zero_time_constant_disables_persistence() {
+        let mut filter = PhosphorFilter::new(Duration::ZERO);
+        filter.update(&screen_with(&[0]), FRAME);
+        assert_eq!(filter.update(&screen_with(&[]), FRAME)[0], 0.0);
+    }
+}
@@ -0,0 +1,267 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use crate::chip8::PROGRAM_LOAD_ADDRESS;
+use crate::disasm;
+
+// Rows per table in the text report
+const REPORT_LIMIT: usize = 20;
+
+// Code outside of any subroutine is attributed to the entry point
+const ENTRY_POINT: u16 = PROGRAM_LOAD_ADDRESS as u16;
+
+// Counts executed instructions per address and per opcode class, and per
+// subroutine by pairing CALL (2NNN) with RET (00EE). Costs are measured in
+// instructions, which is what the VIP's budget is about.
+#[derive(Clone, Debug, Default)]
+pub struct Profiler {
+    instructions: u64,
+    // Execution count and the last opcode seen at each address
+    addresses: BTreeMap<u16, (u64, u16)>,
+    classes: BTreeMap<&'static str, u64>,
+    call_stack: Vec<Call>,
+    functions: BTreeMap<u16, Function>,
+}
+
+#[derive(Clone, Debug)]
+struct Call {
+    function: u16,
+    call_site: u16,
+    // Instruction count when the call was made
+    started: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Function {
+    calls: u64,
+    // Instructions executed in this function and everything it called
+    inclusive: u64,
+    // Instructions executed per address in this function itself
+    costs: BTreeMap<u16, u64>,
+    // Calls made per call site and callee: count and inclusive cost
+    callees: BTreeMap<(u16, u16), (u64, u64)>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Records an instruction right before it executes
+    pub fn record(&mut self, pc: u16, opcode: u16) {
+        self.instructions += 1;
+        let address = self.addresses.entry(pc).or_default();
+        address.0 += 1;
+        address.1 = opcode;
+        *self
+            .classes
+            .entry(disasm::opcode_class(opcode))
+            .or_default() += 1;
+
+        let function = self.current_function();
+        *self
+            .functions
+            .entry(function)
+            .or_default()
+            .costs
+            .entry(pc)
+            .or_default() += 1;
+
+        if opcode & 0xF000 == 0x2000 {
+            let target = opcode & 0x0FFF;
+            self.functions.entry(target).or_default().calls += 1;
+            self.call_stack.push(Call {
+                function: target,
+                call_site: pc,
+                started: self.instructions,
+            });
+        } else if opcode == 0x00EE
+            && let Some(call) = self.call_stack.pop()
+        {
+            self.finish_call(call);
+        }
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    // Sorted text report of the hottest addresses, opcode classes and
+    // subroutines
+    pub fn write_report(&self, mut writer: impl Write) -> io::Result<()> {
+        let profile = self.finished();
+        let total = profile.instructions.max(1) as f64;
+        let percent = |count: u64| 100.0 * count as f64 / total;
+        writeln!(writer, "Profile: {} instructions", profile.instructions)?;
+
+        writeln!(writer, "\nHottest addresses:")?;
+        let mut addresses: Vec<_> = profile.addresses.iter().collect();
+        addresses.sort_by_key(|(_, (count, _))| std::cmp::Reverse(*count));
+        for (pc, (count, opcode)) in addresses.into_iter().take(REPORT_LIMIT) {
+            writeln!(
+                writer,
+                "{:>12} {:>6.2}%  {:#05x}  {:04X}  {}",
+                count,
+                percent(*count),
+                pc,
+                opcode,
+                disasm::disassemble(*opcode)
+            )?;
+        }
+
+        writeln!(writer, "\nOpcode classes:")?;
+        let mut classes: Vec<_> = profile.classes.iter().collect();
+        classes.sort_by_key(|(_, count)| std::cmp::Reverse(**count));
+        for (class, count) in classes {
+            writeln!(writer, "{:>12} {:>6.2}%  {}", count, percent(*count), class)?;
+        }
+
+        writeln!(writer, "\nSubroutines (instructions including callees):")?;
+        let mut functions: Vec<_> = profile
+            .functions
+            .iter()
+            .filter(|(_, function)| function.calls > 0)
+            .collect();
+        functions.sort_by_key(|(_, function)| std::cmp::Reverse(function.inclusive));
+        for (address, function) in functions.into_iter().take(REPORT_LIMIT) {
+            writeln!(
+                writer,
+                "{:>12} {:>6.2}%  {:#05x}  {} calls",
+                function.inclusive,
+                percent(function.inclusive),
+                address,
+                function.calls
+            )?;
+        }
+        Ok(())
+    }
+
+    // Writes the profile in callgrind's format, for KCachegrind and friends:
+    // https://valgrind.org/docs/manual/cl-format.html
+    pub fn write_callgrind(&self, mut writer: impl Write) -> io::Result<()> {
+        let profile = self.finished();
+        writeln!(writer, "# callgrind format")?;
+        writeln!(writer, "version: 1")?;
+        writeln!(writer, "creator: chip-8")?;
+        writeln!(writer, "positions: instr")?;
+        writeln!(writer, "events: Instructions")?;
+        writeln!(writer, "summary: {}", profile.instructions)?;
+
+        for (address, function) in &profile.functions {
+            writeln!(writer, "\nfn={}", function_name(*address))?;
+            for (pc, count) in &function.costs {
+                writeln!(writer, "{:#x} {}", pc, count)?;
+            }
+            for ((call_site, callee), (calls, inclusive)) in &function.callees {
+                writeln!(writer, "cfn={}", function_name(*callee))?;
+                writeln!(writer, "calls={} {:#x}", calls, callee)?;
+                writeln!(writer, "{:#x} {}", call_site, inclusive)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn current_function(&self) -> u16 {
+        self.call_stack
+            .last()
+            .map_or(ENTRY_POINT, |call| call.function)
+    }
+
+    fn finish_call(&mut self, call: Call) {
+        let cost = self.instructions - call.started;
+        self.functions.entry(call.function).or_default().inclusive += cost;
+        let caller = self.current_function();
+        let callee = self
+            .functions
+            .entry(caller)
+            .or_default()
+            .callees
+            .entry((call.call_site, call.function))
+            .or_default();
+        callee.0 += 1;
+        callee.1 += cost;
+    }
+
+    // A copy with subroutines that haven't returned yet (e.g. a main loop
+    // inside a subroutine) finished, so their costs show up
+    fn finished(&self) -> Profiler {
+        let mut profile = self.clone();
+        while let Some(call) = profile.call_stack.pop() {
+            profile.finish_call(call);
+        }
+        profile.functions.entry(ENTRY_POINT).or_default().inclusive = profile.instructions;
+        profile
+    }
+}
+
+fn function_name(address: u16) -> String {
+    match address {
+        ENTRY_POINT => "main".to_string(),
+        _ => format!("sub_{:03X}", address),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // CALL 0x300 twice, the subroutine runs two instructions and returns
+    fn profile() -> Profiler {
+        let mut profiler = Profiler::new();
+        for call_site in [0x200, 0x202] {
+            profiler.record(call_site, 0x2300);
+            profiler.record(0x300, 0x7001);
+            profiler.record(0x302, 0x00EE);
+        }
+        profiler.record(0x204, 0x1204);
+        profiler
+    }
+
+    #[test]
+    fn it_attributes_instructions_to_subroutines() {
+        let profile = profile().finished();
+        assert_eq!(profile.instructions(), 7);
+        assert_eq!(profile.addresses[&0x300], (2, 0x7001));
+        assert_eq!(profile.classes["2NNN"], 2);
+
+        let subroutine = &profile.functions[&0x300];
+        assert_eq!(subroutine.calls, 2);
+        assert_eq!(subroutine.inclusive, 4);
+        assert_eq!(subroutine.costs[&0x302], 2);
+
+        let main = &profile.functions[&ENTRY_POINT];
+        assert_eq!(main.costs.values().sum::<u64>(), 3);
+        assert_eq!(main.callees[&(0x202, 0x300)], (1, 2));
+    }
+
+    #[test]
+    fn it_counts_subroutines_that_never_return() {
+        let mut profiler = Profiler::new();
+        profiler.record(0x200, 0x2300);
+        profiler.record(0x300, 0x1300);
+        profiler.record(0x300, 0x1300);
+        assert_eq!(profiler.finished().functions[&0x300].inclusive, 2);
+    }
+
+    #[test]
+    fn it_writes_a_sorted_report() {
+        let mut report = Vec::new();
+        profile().write_report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with("Profile: 7 instructions"));
+        let hottest = report.lines().nth(3).unwrap();
+        assert!(hottest.contains("0x300"));
+        assert!(report.contains("0x300  2 calls"));
+    }
+
+    #[test]
+    fn it_writes_callgrind_files() {
+        let mut output = Vec::new();
+        profile().write_callgrind(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("events: Instructions\nsummary: 7\n"));
+        assert!(output.contains("fn=main\n0x200 1\n0x202 1\n0x204 1\n"));
+        assert!(output.contains("cfn=sub_300\ncalls=1 0x300\n0x200 2\n"));
+        assert!(output.contains("fn=sub_300\n0x300 2\n0x302 2\n"));
+    }
+}
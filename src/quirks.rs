@@ -0,0 +1,123 @@
+use std::fmt;
+
+// Behaviours that differ between CHIP-8 interpreters. The defaults keep this
+// emulator's original behaviour, which is a mix of the COSMAC VIP and later
+// interpreters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quirks {
+    // FX0A finishes as soon as any key is held, instead of waiting for a key
+    // to be pressed and released
+    pub key_wait_on_press: bool,
+    // 8XY6/8XYE shift VY into VX like the VIP, instead of shifting VX in place
+    pub shift_uses_vy: bool,
+    // FX55/FX65 leave I pointing after the last register like the VIP
+    pub load_store_increments_i: bool,
+    // BNNN jumps to XNN + VX like CHIP-48 and SUPER-CHIP, instead of NNN + V0
+    pub jump_uses_vx: bool,
+    // 8XY1/8XY2/8XY3 reset VF like the VIP
+    pub logic_resets_vf: bool,
+    // Sprites are clipped at the screen edges instead of wrapping around
+    pub clip_sprites: bool,
+}
+
+// Quirk names as used on the command line
+pub const QUIRK_NAMES: [&str; 6] = [
+    "key-wait-on-press",
+    "shift-vy",
+    "load-store-i",
+    "jump-vx",
+    "logic-vf",
+    "clip",
+];
+
+impl Quirks {
+    // Turns a quirk on or off by its name in QUIRK_NAMES
+    pub fn set(&mut self, name: &str, enabled: bool) -> Result<(), String> {
+        let quirk = self
+            .flag_mut(name)
+            .ok_or_else(|| format!("Unknown quirk: {}", name))?;
+        *quirk = enabled;
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<bool> {
+        let mut quirks = *self;
+        quirks.flag_mut(name).map(|&mut enabled| enabled)
+    }
+
+    fn flag_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "key-wait-on-press" => Some(&mut self.key_wait_on_press),
+            "shift-vy" => Some(&mut self.shift_uses_vy),
+            "load-store-i" => Some(&mut self.load_store_increments_i),
+            "jump-vx" => Some(&mut self.jump_uses_vx),
+            "logic-vf" => Some(&mut self.logic_resets_vf),
+            "clip" => Some(&mut self.clip_sprites),
+            _ => None,
+        }
+    }
+}
+
+// Parses a quirk setting as given on the command line, "name" or
+// "name=on|off"
+pub fn parse_quirk(arg: &str) -> Result<(String, bool), String> {
+    let (name, enabled) = match arg.split_once('=') {
+        Some((name, "on")) => (name, true),
+        Some((name, "off")) => (name, false),
+        Some(_) => return Err("expected on or off".to_string()),
+        None => (arg, true),
+    };
+    Quirks::default().set(name, enabled)?;
+    Ok((name.to_string(), enabled))
+}
+
+// The names of the enabled quirks, like "shift-vy, clip"
+impl fmt::Display for Quirks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let enabled: Vec<&str> = QUIRK_NAMES
+            .into_iter()
+            .filter(|name| self.get(name) == Some(true))
+            .collect();
+        match &enabled[..] {
+            [] => write!(f, "none"),
+            names => write!(f, "{}", names.join(", ")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_sets_quirks_by_name() {
+        let mut quirks = Quirks::default();
+        for name in QUIRK_NAMES {
+            quirks.set(name, true).unwrap();
+        }
+        assert!(quirks.shift_uses_vy && quirks.clip_sprites && quirks.key_wait_on_press);
+        quirks.set("clip", false).unwrap();
+        assert!(!quirks.clip_sprites);
+        assert_eq!(quirks.get("clip"), Some(false));
+        assert!(quirks.set("vblank", true).is_err());
+    }
+
+    #[test]
+    fn it_parses_quirk_settings() {
+        assert_eq!(parse_quirk("clip"), Ok(("clip".to_string(), true)));
+        assert_eq!(parse_quirk("clip=off"), Ok(("clip".to_string(), false)));
+        assert!(parse_quirk("clip=maybe").is_err());
+        assert!(parse_quirk("vblank").is_err());
+    }
+
+    #[test]
+    fn it_lists_enabled_quirks() {
+        assert_eq!(Quirks::default().to_string(), "none");
+        let quirks = Quirks {
+            shift_uses_vy: true,
+            clip_sprites: true,
+            ..Quirks::default()
+        };
+        assert_eq!(quirks.to_string(), "shift-vy, clip");
+    }
+}
@@ -0,0 +1,186 @@
+use std::io::Write;
+use std::time::Duration;
+
+use crate::chip8::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use crate::palette::Palette;
+use crate::screenshot::upscale;
+
+// GIF frame delays are specified in hundredths of a second
+const GIF_TIME_UNIT: Duration = Duration::from_millis(10);
+
+// Encodes captured screens as an endlessly looping animated GIF.
+//
+// Every captured frame is held back until the next one arrives so that its
+// delay is known. With `only_on_change` identical consecutive frames are
+// merged into one, which keeps recordings of mostly static games small.
+pub struct GifRecorder<W: Write> {
+    encoder: gif::Encoder<W>,
+    scale: usize,
+    only_on_change: bool,
+    pending: Option<Vec<bool>>,
+    // Total recorded time and the part of it already written as delays, so
+    // rounding to GIF time units doesn't accumulate
+    elapsed: Duration,
+    written: u64,
+}
+
+impl<W: Write> GifRecorder<W> {
+    pub fn new(
+        writer: W,
+        scale: usize,
+        palette: &Palette,
+        only_on_change: bool,
+    ) -> Result<Self, String> {
+        let width = DISPLAY_WIDTH * scale;
+        let height = DISPLAY_HEIGHT * scale;
+        if scale == 0 || width > u16::MAX as usize || height > u16::MAX as usize {
+            return Err(format!("Invalid recording scale {}", scale));
+        }
+
+        let global_palette = [palette.background, palette.foreground].concat();
+        let mut encoder = gif::Encoder::new(writer, width as u16, height as u16, &global_palette)
+            .map_err(|e| e.to_string())?;
+        encoder
+            .set_repeat(gif::Repeat::Infinite)
+            .map_err(|e| e.to_string())?;
+
+        Ok(GifRecorder {
+            encoder,
+            scale,
+            only_on_change,
+            pending: None,
+            elapsed: Duration::ZERO,
+            written: 0,
+        })
+    }
+
+    // Records a screen that stays visible for `frame_time`
+    pub fn capture(&mut self, screen: &[bool], frame_time: Duration) -> Result<(), String> {
+        let unchanged = self.pending.as_deref() == Some(screen);
+        if !(self.only_on_change && unchanged) {
+            self.flush()?;
+            self.pending = Some(screen.to_vec());
+        }
+        self.elapsed += frame_time;
+        Ok(())
+    }
+
+    // Writes the last frame and the GIF trailer, returning the writer
+    pub fn finish(mut self) -> Result<W, String> {
+        self.flush()?;
+        self.encoder.into_inner().map_err(|e| e.to_string())
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        let Some(screen) = self.pending.take() else {
+            return Ok(());
+        };
+
+        let total = (self.elapsed.as_secs_f64() / GIF_TIME_UNIT.as_secs_f64()).round() as u64;
+        let delay = (total - self.written).min(u16::MAX as u64);
+        self.written += delay;
+
+        let mut frame = gif::Frame::from_indexed_pixels(
+            (DISPLAY_WIDTH * self.scale) as u16,
+            (DISPLAY_HEIGHT * self.scale) as u16,
+            upscale(&screen, self.scale, |lit| lit as u8),
+            None,
+        );
+        frame.delay = delay as u16;
+        self.encoder.write_frame(&frame).map_err(|e| e.to_string())
+    }
+}
+
+// Writes every captured screen as raw RGB24 data, e.g. for piping into
+// `ffmpeg -f rawvideo -pixel_format rgb24 -video_size 640x320 -framerate 60 -i -`
+pub struct RawFrameWriter<W: Write> {
+    writer: W,
+    scale: usize,
+    palette: Palette,
+}
+
+impl<W: Write> RawFrameWriter<W> {
+    pub fn new(writer: W, scale: usize, palette: &Palette) -> Self {
+        RawFrameWriter {
+            writer,
+            scale,
+            palette: *palette,
+        }
+    }
+
+    pub fn capture(&mut self, screen: &[bool]) -> Result<(), String> {
+        let data = upscale(screen, self.scale, |lit| self.palette.color(lit)).into_flattened();
+        self.writer.write_all(&data).map_err(|e| e.to_string())
+    }
+
+    pub fn finish(mut self) -> Result<W, String> {
+        self.writer.flush().map_err(|e| e.to_string())?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: Duration = Duration::from_micros(16667);
+
+    fn blank_screen() -> Vec<bool> {
+        vec![false; DISPLAY_WIDTH * DISPLAY_HEIGHT]
+    }
+
+    fn lit_screen() -> Vec<bool> {
+        let mut screen = blank_screen();
+        screen[0] = true;
+        screen
+    }
+
+    fn decode_delays(data: Vec<u8>) -> Vec<u16> {
+        let mut decoder = gif::DecodeOptions::new()
+            .read_info(std::io::Cursor::new(data))
+            .unwrap();
+        let mut delays = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            delays.push(frame.delay);
+        }
+        delays
+    }
+
+    #[test]
+    fn it_records_every_frame_without_drifting() {
+        let mut recorder = GifRecorder::new(Vec::new(), 1, &Palette::default(), false).unwrap();
+        for _ in 0..6 {
+            recorder.capture(&blank_screen(), FRAME).unwrap();
+        }
+        let delays = decode_delays(recorder.finish().unwrap());
+        assert_eq!(delays.len(), 6);
+        assert_eq!(delays.iter().sum::<u16>(), 10);
+    }
+
+    #[test]
+    fn it_merges_unchanged_frames_when_recording_changes_only() {
+        let mut recorder = GifRecorder::new(Vec::new(), 2, &Palette::default(), true).unwrap();
+        for _ in 0..3 {
+            recorder.capture(&blank_screen(), FRAME).unwrap();
+        }
+        for _ in 0..3 {
+            recorder.capture(&lit_screen(), FRAME).unwrap();
+        }
+        assert_eq!(decode_delays(recorder.finish().unwrap()), vec![5, 5]);
+    }
+
+    #[test]
+    fn it_writes_raw_rgb_frames() {
+        let palette = Palette::new([1, 1, 1], [9, 9, 9]);
+        let mut writer = RawFrameWriter::new(Vec::new(), 2, &palette);
+        writer.capture(&lit_screen()).unwrap();
+        writer.capture(&blank_screen()).unwrap();
+        let data = writer.finish().unwrap();
+
+        let frame_size = DISPLAY_WIDTH * DISPLAY_HEIGHT * 4 * 3;
+        assert_eq!(data.len(), 2 * frame_size);
+        assert_eq!(data[..6], [9; 6]);
+        assert_eq!(data[6..9], [1; 3]);
+        assert_eq!(data[frame_size..frame_size + 3], [1; 3]);
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Deserialize;
+
+use crate::keypad::Key;
+use crate::palette::{self, Palette};
+use crate::quirks::Quirks;
+
+// A subset of the community CHIP-8 database
+// (https://github.com/chip-8/chip-8-database), with its platforms.json and
+// programs.json merged into one file. Entries for other ROMs can be copied
+// over as they are.
+const BUNDLED: &str = include_str!("../data/rom-database.json");
+
+// What the database knows about a ROM
+#[derive(Clone, Debug, PartialEq)]
+pub struct RomInfo {
+    pub title: String,
+    pub platform: String,
+    pub quirks: Quirks,
+    // Instructions per frame
+    pub tick_rate: Option<usize>,
+    pub palette: Option<Palette>,
+    // CHIP-8 keys for controls like "up", "left" or "a"
+    pub keys: BTreeMap<String, Key>,
+}
+
+// ROMs indexed by the SHA-1 hash of the file
+#[derive(Debug)]
+pub struct RomDatabase {
+    platforms: HashMap<String, PlatformQuirks>,
+    roms: HashMap<String, (String, Rom)>,
+}
+
+#[derive(Deserialize)]
+struct Database {
+    platforms: Vec<Platform>,
+    programs: Vec<Program>,
+}
+
+#[derive(Deserialize)]
+struct Platform {
+    id: String,
+    quirks: PlatformQuirks,
+}
+
+// The database's quirk flags. CHIP-48's off-by-one increment of I
+// (memoryIncrementByX) and waiting for the display interrupt (vblank) aren't
+// emulated, so those are ignored.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct PlatformQuirks {
+    shift: bool,
+    memory_leave_i_unchanged: bool,
+    wrap: bool,
+    jump: bool,
+    logic: bool,
+}
+
+// Per-ROM deviations from a platform's quirks
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct QuirkOverrides {
+    shift: Option<bool>,
+    memory_leave_i_unchanged: Option<bool>,
+    wrap: Option<bool>,
+    jump: Option<bool>,
+    logic: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct Program {
+    title: String,
+    roms: HashMap<String, Rom>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Rom {
+    platforms: Vec<String>,
+    #[serde(default)]
+    quirky_platforms: HashMap<String, QuirkOverrides>,
+    tickrate: Option<usize>,
+    colors: Option<Colors>,
+    #[serde(default)]
+    keys: BTreeMap<String, u8>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Colors {
+    // Background first, then the colors of lit pixels
+    pixels: Vec<String>,
+}
+
+impl RomDatabase {
+    pub fn bundled() -> Self {
+        Self::from_json(BUNDLED).expect("The bundled ROM database is valid")
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let database: Database =
+            serde_json::from_str(json).map_err(|e| format!("Invalid ROM database: {}", e))?;
+        let platforms = database
+            .platforms
+            .into_iter()
+            .map(|platform| (platform.id, platform.quirks))
+            .collect();
+        let roms = database
+            .programs
+            .into_iter()
+            .flat_map(|program| {
+                let title = program.title;
+                program
+                    .roms
+                    .into_iter()
+                    .map(move |(hash, rom)| (hash.to_ascii_lowercase(), (title.clone(), rom)))
+            })
+            .collect();
+        Ok(RomDatabase { platforms, roms })
+    }
+
+    pub fn lookup(&self, rom: &[u8]) -> Option<RomInfo> {
+        let (title, entry) = self.roms.get(&sha1_hex(rom))?;
+        // The first platform listed is the one the ROM was made for
+        let platform = entry.platforms.first()?;
+        let mut quirks = self.platforms.get(platform).copied().unwrap_or_default();
+        if let Some(overrides) = entry.quirky_platforms.get(platform) {
+            quirks.apply(overrides);
+        }
+
+        let palette = entry
+            .colors
+            .as_ref()
+            .and_then(|colors| match &colors.pixels[..] {
+                [background, foreground, ..] => Some(Palette::new(
+                    palette::parse_color(background)?,
+                    palette::parse_color(foreground)?,
+                )),
+                _ => None,
+            });
+        let keys = entry
+            .keys
+            .iter()
+            .filter_map(|(control, &key)| Some((control.clone(), Key::new(key)?)))
+            .collect();
+
+        Some(RomInfo {
+            title: title.clone(),
+            platform: platform.clone(),
+            quirks: quirks.into(),
+            tick_rate: entry.tickrate,
+            palette,
+            keys,
+        })
+    }
+
+    // Quirks of a platform by its database id, like "originalChip8"
+    pub fn platform_quirks(&self, id: &str) -> Option<Quirks> {
+        self.platforms.get(id).map(|&quirks| quirks.into())
+    }
+}
+
+impl PlatformQuirks {
+    fn apply(&mut self, overrides: &QuirkOverrides) {
+        self.shift = overrides.shift.unwrap_or(self.shift);
+        self.memory_leave_i_unchanged = overrides
+            .memory_leave_i_unchanged
+            .unwrap_or(self.memory_leave_i_unchanged);
+        self.wrap = overrides.wrap.unwrap_or(self.wrap);
+        self.jump = overrides.jump.unwrap_or(self.jump);
+        self.logic = overrides.logic.unwrap_or(self.logic);
+    }
+}
+
+impl From<PlatformQuirks> for Quirks {
+    fn from(quirks: PlatformQuirks) -> Self {
+        Quirks {
+            shift_uses_vy: !quirks.shift,
+            load_store_increments_i: !quirks.memory_leave_i_unchanged,
+            jump_uses_vx: quirks.jump,
+            logic_resets_vf: quirks.logic,
+            clip_sprites: !quirks.wrap,
+            ..Quirks::default()
+        }
+    }
+}
+
+pub fn sha1_hex(data: &[u8]) -> String {
+    sha1_smol::Sha1::from(data).digest().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: [u8; 2] = [0x12, 0x00];
+
+    fn database() -> RomDatabase {
+        let json = format!(
+            r##"{{
+                "platforms": [
+                    {{"id": "originalChip8", "quirks": {{"logic": true, "vblank": true}}}},
+                    {{"id": "superchip", "quirks": {{"shift": true, "jump": true, "wrap": false}}}}
+                ],
+                "programs": [{{
+                    "title": "Loop",
+                    "roms": {{"{}": {{
+                        "platforms": ["superchip", "originalChip8"],
+                        "quirkyPlatforms": {{"superchip": {{"jump": false}}}},
+                        "tickrate": 30,
+                        "colors": {{"pixels": ["#102030", "#f0e0d0"], "buzzer": "#ffaa00"}},
+                        "keys": {{"up": 5, "a": 6, "player2Up": 16}}
+                    }}}}
+                }}]
+            }}"##,
+            sha1_hex(&ROM).to_uppercase()
+        );
+        RomDatabase::from_json(&json).unwrap()
+    }
+
+    #[test]
+    fn it_hashes_roms_with_sha1() {
+        assert_eq!(sha1_hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    }
+
+    #[test]
+    fn it_looks_up_roms_by_hash() {
+        let info = database().lookup(&ROM).unwrap();
+        assert_eq!(info.title, "Loop");
+        assert_eq!(info.platform, "superchip");
+        assert_eq!(
+            info.quirks,
+            Quirks {
+                clip_sprites: true,
+                load_store_increments_i: true,
+                ..Quirks::default()
+            }
+        );
+        assert_eq!(info.tick_rate, Some(30));
+        assert_eq!(
+            info.palette,
+            Some(Palette::new([0x10, 0x20, 0x30], [0xf0, 0xe0, 0xd0]))
+        );
+        assert_eq!(
+            info.keys,
+            BTreeMap::from([
+                ("a".to_string(), Key::new(6).unwrap()),
+                ("up".to_string(), Key::new(5).unwrap()),
+            ])
+        );
+        assert!(database().lookup(&[0x00, 0xE0]).is_none());
+    }
+
+    #[test]
+    fn it_knows_platform_quirks() {
+        let quirks = database().platform_quirks("originalChip8").unwrap();
+        assert!(quirks.logic_resets_vf && quirks.shift_uses_vy && quirks.clip_sprites);
+        assert!(database().platform_quirks("megachip").is_none());
+    }
+
+    #[test]
+    fn it_bundles_a_valid_database() {
+        let database = RomDatabase::bundled();
+        assert!(database.platform_quirks("xochip").is_some());
+        assert!(database.roms.values().all(|(_, rom)| {
+            rom.platforms
+                .iter()
+                .all(|platform| database.platforms.contains_key(platform))
+        }));
+    }
+}
@@ -0,0 +1,140 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::chip8::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use crate::palette::Palette;
+
+// Encodes a CHIP-8 screen as an RGB PNG, each pixel scaled up to a
+// `scale` x `scale` square
+pub fn write_png<W: Write>(
+    writer: W,
+    screen: &[bool],
+    scale: usize,
+    palette: &Palette,
+) -> Result<(), String> {
+    if scale == 0 {
+        return Err("Screenshot scale must be at least 1".to_string());
+    }
+
+    let width = DISPLAY_WIDTH * scale;
+    let height = DISPLAY_HEIGHT * scale;
+    let data = upscale(screen, scale, |lit| palette.color(lit)).into_flattened();
+
+    let mut encoder = png::Encoder::new(writer, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+    writer.write_image_data(&data).map_err(|e| e.to_string())?;
+    writer.finish().map_err(|e| e.to_string())
+}
+
+// Expands each screen pixel into a `scale` x `scale` block of `pixel(lit)`,
+// row by row
+pub(crate) fn upscale<T: Copy>(screen: &[bool], scale: usize, pixel: impl Fn(bool) -> T) -> Vec<T> {
+    let mut data = Vec::with_capacity(screen.len() * scale * scale);
+    for row in screen.chunks(DISPLAY_WIDTH) {
+        let line: Vec<T> = row
+            .iter()
+            .flat_map(|&lit| std::iter::repeat_n(pixel(lit), scale))
+            .collect();
+        for _ in 0..scale {
+            data.extend_from_slice(&line);
+        }
+    }
+    data
+}
+
+pub fn save_png(
+    path: &Path,
+    screen: &[bool],
+    scale: usize,
+    palette: &Palette,
+) -> Result<(), String> {
+    let file = File::create(path)
+        .map_err(|e| format!("Cannot create screenshot {}: {}", path.display(), e))?;
+    write_png(BufWriter::new(file), screen, scale, palette)
+}
+
+// Builds a file name like `dir/chip8-20240131-235959-123.png` from the
+// current UTC time
+pub fn timestamped_path(dir: &Path, extension: &str) -> PathBuf {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    dir.join(format!(
+        "chip8-{}-{:03}.{}",
+        format_timestamp(now.as_secs()),
+        now.subsec_millis(),
+        extension
+    ))
+}
+
+// Formats seconds since the epoch as YYYYMMDD-HHMMSS, using the
+// days-to-civil conversion from http://howardhinnant.github.io/date_algorithms.html
+fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64 + 719468;
+    let seconds = secs % 86400;
+
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_formats_timestamps_as_utc_dates() {
+        assert_eq!(format_timestamp(0), "19700101-000000");
+        assert_eq!(format_timestamp(951782400), "20000229-000000");
+        assert_eq!(format_timestamp(1700000000), "20231114-221320");
+    }
+
+    #[test]
+    fn it_writes_a_scaled_png() {
+        let mut screen = vec![false; DISPLAY_WIDTH * DISPLAY_HEIGHT];
+        screen[1] = true;
+        let palette = Palette::new([1, 2, 3], [4, 5, 6]);
+
+        let mut png_data = Vec::new();
+        write_png(&mut png_data, &screen, 2, &palette).unwrap();
+
+        let decoder = png::Decoder::new(std::io::Cursor::new(png_data));
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size().unwrap()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+
+        assert_eq!((info.width, info.height), (128, 64));
+        assert_eq!(pixels[..3], [1, 2, 3]);
+        assert_eq!(pixels[6..9], [4, 5, 6]);
+        assert_eq!(pixels[9..12], [4, 5, 6]);
+        assert_eq!(pixels[12..15], [1, 2, 3]);
+        // Second line is a copy of the first
+        assert_eq!(pixels[128 * 3 + 6..128 * 3 + 9], [4, 5, 6]);
+    }
+
+    #[test]
+    fn it_rejects_a_zero_scale() {
+        let screen = vec![false; DISPLAY_WIDTH * DISPLAY_HEIGHT];
+        assert!(write_png(Vec::new(), &screen, 0, &Palette::default()).is_err());
+    }
+}
@@ -1,59 +1,80 @@
-use crate::chip8;
+use std::cell::RefCell;
+use std::rc::Rc;
 
+use crate::chip8::{self, AUDIO_PATTERN_SIZE};
+
+use chip_8::synth::{DEFAULT_SAMPLE_RATE, Synth, ToneSettings};
 use sdl2::AudioSubsystem;
 use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
 
-pub struct SquareWave {
-    phase_inc: f32,
-    phase: f32,
-    volume: f32,
+pub struct Beeper {
+    synth: Synth,
 }
 
-impl AudioCallback for SquareWave {
+impl AudioCallback for Beeper {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
-        for x in out.iter_mut() {
-            *x = if self.phase <= 0.5 {
-                self.volume
-            } else {
-                -self.volume
-            };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
-        }
+        self.synth.fill(out);
     }
 }
 
+// Cloning yields another handle to the same audio device, so the frontend can
+// keep adjusting the tone after handing the speaker to `Chip8`
+#[derive(Clone)]
 pub struct SDLSpeaker {
-    pub device: AudioDevice<SquareWave>,
+    device: Rc<RefCell<AudioDevice<Beeper>>>,
+    playing: bool,
 }
 
 impl SDLSpeaker {
-    pub fn new(audio_subsystem: &AudioSubsystem) -> Self {
+    pub fn new(audio_subsystem: &AudioSubsystem, settings: ToneSettings) -> Self {
         let spec = AudioSpecDesired {
-            freq: Some(5000),
+            freq: Some(DEFAULT_SAMPLE_RATE as i32),
             channels: Some(1),
             samples: None,
         };
 
         let device = audio_subsystem
-            .open_playback(None, &spec, |spec| SquareWave {
-                phase_inc: 440.0 / spec.freq as f32,
-                phase: 0.0,
-                volume: 0.25,
+            .open_playback(None, &spec, |spec| Beeper {
+                synth: Synth::new(spec.freq as u32, settings),
             })
             .expect("Could not initialize audio device");
 
-        SDLSpeaker { device }
+        // The device keeps running, the synth's gate switches the tone on and
+        // off
+        device.resume();
+
+        SDLSpeaker {
+            device: Rc::new(RefCell::new(device)),
+            playing: false,
+        }
+    }
+
+    pub fn settings(&self) -> ToneSettings {
+        *self.device.borrow_mut().lock().synth.settings()
+    }
+
+    // Applies `change` to the tone settings while the audio callback is locked
+    pub fn update_settings(&self, change: impl FnOnce(&mut ToneSettings)) {
+        change(self.device.borrow_mut().lock().synth.settings_mut());
     }
 }
 
 impl chip8::Speaker for SDLSpeaker {
     fn beep(&mut self, status: bool) {
-        if status {
-            self.device.resume();
-        } else {
-            self.device.pause();
+        // Called every frame, only lock the audio thread when something changes
+        if status != self.playing {
+            self.playing = status;
+            self.device.borrow_mut().lock().synth.set_gate(status);
         }
     }
+
+    fn set_pattern(&mut self, pattern: &[u8; AUDIO_PATTERN_SIZE], rate: f32) {
+        self.device
+            .borrow_mut()
+            .lock()
+            .synth
+            .set_pattern(*pattern, rate);
+    }
 }
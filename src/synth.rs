@@ -0,0 +1,343 @@
+use std::f32::consts::TAU;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::chip8::AUDIO_PATTERN_SIZE;
+
+const PATTERN_BITS: f32 = (AUDIO_PATTERN_SIZE * 8) as f32;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 48000;
+pub const DEFAULT_PITCH: f32 = 440.0;
+// Audible range the pitch is kept in
+pub const MIN_PITCH: f32 = 20.0;
+pub const MAX_PITCH: f32 = 20_000.0;
+pub const DEFAULT_VOLUME: f32 = 0.25;
+
+// Length of the fade in/out when the tone is switched, short enough to be
+// inaudible but long enough to avoid clicks
+const RAMP_TIME: f32 = 0.005;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Waveform {
+    #[default]
+    Square,
+    Sine,
+    Triangle,
+    Noise,
+}
+
+impl Waveform {
+    pub const ALL: [Waveform; 4] = [
+        Waveform::Square,
+        Waveform::Sine,
+        Waveform::Triangle,
+        Waveform::Noise,
+    ];
+
+    pub fn next(self) -> Waveform {
+        let index = Waveform::ALL.iter().position(|&w| w == self).unwrap_or(0);
+        Waveform::ALL[(index + 1) % Waveform::ALL.len()]
+    }
+}
+
+impl FromStr for Waveform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "square" => Ok(Waveform::Square),
+            "sine" => Ok(Waveform::Sine),
+            "triangle" => Ok(Waveform::Triangle),
+            "noise" => Ok(Waveform::Noise),
+            _ => Err(format!("Unknown waveform: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Waveform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Waveform::Square => "square",
+            Waveform::Sine => "sine",
+            Waveform::Triangle => "triangle",
+            Waveform::Noise => "noise",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ToneSettings {
+    // Frequency in Hz
+    pub pitch: f32,
+    // Amplitude between 0 and 1
+    pub volume: f32,
+    pub waveform: Waveform,
+    pub muted: bool,
+}
+
+impl Default for ToneSettings {
+    fn default() -> Self {
+        ToneSettings {
+            pitch: DEFAULT_PITCH,
+            volume: DEFAULT_VOLUME,
+            waveform: Waveform::default(),
+            muted: false,
+        }
+    }
+}
+
+// Generates the beeper tone as mono f32 samples. The tone is switched with a
+// gate and ramps up and down instead of starting or stopping mid-wave.
+pub struct Synth {
+    sample_rate: u32,
+    settings: ToneSettings,
+    gate: bool,
+    envelope: f32,
+    phase: f32,
+    // XO-CHIP audio pattern and its playback rate, replaces the waveform
+    pattern: Option<([u8; AUDIO_PATTERN_SIZE], f32)>,
+    pattern_position: f32,
+    // Noise is a random level held for half a period, drawn from a
+    // xorshift generator so the output is reproducible
+    noise_state: u32,
+    noise_level: f32,
+}
+
+impl Synth {
+    pub fn new(sample_rate: u32, settings: ToneSettings) -> Self {
+        Synth {
+            sample_rate,
+            settings,
+            gate: false,
+            envelope: 0.0,
+            phase: 0.0,
+            pattern: None,
+            pattern_position: 0.0,
+            noise_state: 0x2545_f491,
+            noise_level: 1.0,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn settings(&self) -> &ToneSettings {
+        &self.settings
+    }
+
+    pub fn settings_mut(&mut self) -> &mut ToneSettings {
+        &mut self.settings
+    }
+
+    pub fn gate(&self) -> bool {
+        self.gate
+    }
+
+    pub fn set_gate(&mut self, on: bool) {
+        self.gate = on;
+    }
+
+    pub fn set_pattern(&mut self, pattern: [u8; AUDIO_PATTERN_SIZE], rate: f32) {
+        self.pattern = Some((pattern, rate));
+    }
+
+    pub fn clear_pattern(&mut self) {
+        self.pattern = None;
+    }
+
+    pub fn fill(&mut self, out: &mut [f32]) {
+        for sample in out.iter_mut() {
+            *sample = self.next_sample();
+        }
+    }
+
+    fn next_sample(&mut self) -> f32 {
+        // Muting goes through the envelope as well, so it doesn't click either
+        let target = if self.gate && !self.settings.muted {
+            1.0
+        } else {
+            0.0
+        };
+        let ramp_step = 1.0 / (RAMP_TIME * self.sample_rate as f32);
+        self.envelope = if self.envelope < target {
+            (self.envelope + ramp_step).min(target)
+        } else {
+            (self.envelope - ramp_step).max(target)
+        };
+
+        if self.envelope == 0.0 {
+            // Start the next tone at the beginning of a wave
+            self.phase = 0.0;
+            self.pattern_position = 0.0;
+            return 0.0;
+        }
+
+        let volume = self.envelope * self.settings.volume.clamp(0.0, 1.0);
+        if let Some((pattern, rate)) = self.pattern {
+            return self.next_pattern_sample(&pattern, rate) * volume;
+        }
+
+        let phase = self.phase;
+        let value = match self.settings.waveform {
+            Waveform::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Sine => (phase * TAU).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Waveform::Noise => self.noise_level,
+        };
+
+        let phase_inc = self.settings.pitch / self.sample_rate as f32;
+        let next_phase = (phase + phase_inc) % 1.0;
+        if (phase < 0.5) != (next_phase < 0.5) {
+            self.noise_level = self.next_noise();
+        }
+        self.phase = next_phase;
+
+        value * volume
+    }
+
+    fn next_pattern_sample(&mut self, pattern: &[u8; AUDIO_PATTERN_SIZE], rate: f32) -> f32 {
+        let bit = self.pattern_position as usize;
+        let set = pattern[bit / 8] & (0x80 >> (bit % 8)) != 0;
+        self.pattern_position =
+            (self.pattern_position + rate / self.sample_rate as f32) % PATTERN_BITS;
+        if set { 1.0 } else { -1.0 }
+    }
+
+    fn next_noise(&mut self) -> f32 {
+        let mut x = self.noise_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.noise_state = x;
+        x as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(settings: ToneSettings, samples: usize) -> Vec<f32> {
+        let mut synth = Synth::new(8, settings);
+        synth.set_gate(true);
+        let mut out = vec![0.0; samples];
+        synth.fill(&mut out);
+        out
+    }
+
+    #[test]
+    fn it_parses_and_cycles_waveforms() {
+        assert_eq!("Triangle".parse(), Ok(Waveform::Triangle));
+        assert!("sawtooth".parse::<Waveform>().is_err());
+        assert_eq!(Waveform::Noise.next(), Waveform::Square);
+        assert_eq!(Waveform::Sine.to_string(), "sine");
+    }
+
+    #[test]
+    fn it_generates_a_square_wave_at_the_given_pitch_and_volume() {
+        let settings = ToneSettings {
+            pitch: 2.0,
+            volume: 0.5,
+            ..ToneSettings::default()
+        };
+        assert_eq!(
+            render(settings, 8),
+            vec![0.5, 0.5, -0.5, -0.5, 0.5, 0.5, -0.5, -0.5]
+        );
+    }
+
+    #[test]
+    fn it_generates_a_triangle_wave() {
+        let settings = ToneSettings {
+            pitch: 2.0,
+            volume: 1.0,
+            waveform: Waveform::Triangle,
+            ..ToneSettings::default()
+        };
+        assert_eq!(render(settings, 4), vec![-1.0, 0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn it_is_silent_when_muted() {
+        let settings = ToneSettings {
+            muted: true,
+            ..ToneSettings::default()
+        };
+        assert!(render(settings, 16).iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn it_is_silent_while_the_gate_is_closed() {
+        let mut synth = Synth::new(8, ToneSettings::default());
+        let mut out = vec![1.0; 16];
+        synth.fill(&mut out);
+        assert!(out.iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn it_ramps_the_tone_up_and_down() {
+        // 5 samples per ramp at 1 kHz, with a wave that is constantly high
+        let settings = ToneSettings {
+            pitch: 1.0,
+            volume: 1.0,
+            ..ToneSettings::default()
+        };
+        let mut synth = Synth::new(1000, settings);
+        let mut out = vec![0.0; 6];
+
+        synth.set_gate(true);
+        synth.fill(&mut out);
+        let expected = [0.2, 0.4, 0.6, 0.8, 1.0, 1.0];
+        assert!(out.iter().zip(expected).all(|(a, b)| (a - b).abs() < 1e-6));
+
+        synth.set_gate(false);
+        synth.fill(&mut out);
+        let expected = [0.8, 0.6, 0.4, 0.2, 0.0, 0.0];
+        assert!(out.iter().zip(expected).all(|(a, b)| (a - b).abs() < 1e-6));
+    }
+
+    #[test]
+    fn it_plays_audio_patterns_bit_by_bit() {
+        let settings = ToneSettings {
+            volume: 1.0,
+            ..ToneSettings::default()
+        };
+        let mut synth = Synth::new(8, settings);
+        let mut pattern = [0; AUDIO_PATTERN_SIZE];
+        pattern[0] = 0b1010_0000;
+        pattern[15] = 0b0000_0001;
+        synth.set_gate(true);
+
+        // Two samples per bit
+        synth.set_pattern(pattern, 4.0);
+        let mut out = vec![0.0; 8];
+        synth.fill(&mut out);
+        assert_eq!(out, vec![1.0, 1.0, -1.0, -1.0, 1.0, 1.0, -1.0, -1.0]);
+
+        // The pattern wraps around after 128 bits
+        let mut out = vec![0.0; 2 * 128 - 8 + 4];
+        synth.fill(&mut out);
+        assert_eq!(out[out.len() - 6..], [1.0, 1.0, 1.0, 1.0, -1.0, -1.0]);
+    }
+
+    #[test]
+    fn noise_stays_within_the_volume() {
+        let settings = ToneSettings {
+            pitch: 3.0,
+            volume: 0.5,
+            waveform: Waveform::Noise,
+            ..ToneSettings::default()
+        };
+        let samples = render(settings, 64);
+        assert!(samples.iter().all(|s| s.abs() <= 0.5));
+        assert!(samples.windows(2).any(|w| w[0] != w[1]));
+    }
+}
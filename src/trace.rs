@@ -0,0 +1,373 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use crate::chip8::Chip8;
+use crate::disasm;
+
+// Machine state right before an instruction executes. Displayed as one trace
+// line:
+//
+//   PC  : opcode  mnemonic            V0..VF                           I    SP DT ST
+//   0204: 7001  ADD V0, 0x01          V=12000000000000000000000000000000 I=0300 SP=00 DT=00 ST=00
+//
+// All numbers are hex. The mnemonic column is padded, so traces of the same
+// ROM line up when diffed. When parsing, the mnemonic is ignored and the
+// register fields may come in any order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub pc: u16,
+    pub opcode: u16,
+    pub v_registers: [u8; 16],
+    pub i: u16,
+    pub sp: u8,
+    pub dt: u8,
+    pub st: u8,
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04X}: {:04X}  {:<20}  V=",
+            self.pc,
+            self.opcode,
+            disasm::disassemble(self.opcode)
+        )?;
+        for v in self.v_registers {
+            write!(f, "{:02X}", v)?;
+        }
+        write!(
+            f,
+            " I={:04X} SP={:02X} DT={:02X} ST={:02X}",
+            self.i, self.sp, self.dt, self.st
+        )
+    }
+}
+
+impl FromStr for TraceEntry {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let malformed = || format!("Malformed trace line: {}", line);
+        let mut tokens = line.split_whitespace();
+        let pc = tokens
+            .next()
+            .and_then(|pc| pc.strip_suffix(':'))
+            .and_then(|pc| u16::from_str_radix(pc, 16).ok())
+            .ok_or_else(malformed)?;
+        let opcode = tokens
+            .next()
+            .and_then(|opcode| u16::from_str_radix(opcode, 16).ok())
+            .ok_or_else(malformed)?;
+
+        let mut v_registers = None;
+        let (mut i, mut sp, mut dt, mut st) = (None, None, None, None);
+        for (key, value) in tokens.filter_map(|token| token.split_once('=')) {
+            match key {
+                "V" => v_registers = parse_v_registers(value),
+                "I" => i = u16::from_str_radix(value, 16).ok(),
+                "SP" => sp = u8::from_str_radix(value, 16).ok(),
+                "DT" => dt = u8::from_str_radix(value, 16).ok(),
+                "ST" => st = u8::from_str_radix(value, 16).ok(),
+                _ => {}
+            }
+        }
+
+        Ok(TraceEntry {
+            pc,
+            opcode,
+            v_registers: v_registers.ok_or_else(malformed)?,
+            i: i.ok_or_else(malformed)?,
+            sp: sp.ok_or_else(malformed)?,
+            dt: dt.ok_or_else(malformed)?,
+            st: st.ok_or_else(malformed)?,
+        })
+    }
+}
+
+impl TraceEntry {
+    // Named, formatted fields for showing two states side by side
+    fn fields(&self) -> Vec<(String, String)> {
+        let mut fields = vec![
+            ("PC".to_string(), format!("{:04X}", self.pc)),
+            ("opcode".to_string(), format!("{:04X}", self.opcode)),
+        ];
+        for (x, v) in self.v_registers.iter().enumerate() {
+            fields.push((format!("V{:X}", x), format!("{:02X}", v)));
+        }
+        fields.push(("I".to_string(), format!("{:04X}", self.i)));
+        fields.push(("SP".to_string(), format!("{:02X}", self.sp)));
+        fields.push(("DT".to_string(), format!("{:02X}", self.dt)));
+        fields.push(("ST".to_string(), format!("{:02X}", self.st)));
+        fields
+    }
+}
+
+fn parse_v_registers(hex: &str) -> Option<[u8; 16]> {
+    let mut v_registers = [0; 16];
+    if hex.len() != v_registers.len() * 2 {
+        return None;
+    }
+    for (x, v) in v_registers.iter_mut().enumerate() {
+        *v = u8::from_str_radix(hex.get(x * 2..x * 2 + 2)?, 16).ok()?;
+    }
+    Some(v_registers)
+}
+
+pub enum Comparison {
+    // The whole reference trace matched, this many instructions long
+    Match(usize),
+    Divergence(Divergence),
+}
+
+pub struct Divergence {
+    // Number of instructions that matched before
+    pub instruction: usize,
+    // Line number in the reference trace, starting at 1
+    pub line: usize,
+    // The last instruction that matched, most likely the culprit
+    pub previous: Option<TraceEntry>,
+    pub expected: TraceEntry,
+    pub actual: TraceEntry,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "State diverges after {} instructions (reference line {})",
+            self.instruction, self.line
+        )?;
+        if let Some(previous) = &self.previous {
+            writeln!(f, "Last instruction: {}", previous)?;
+        }
+        writeln!(f, "        reference   emulator")?;
+        let expected = self.expected.fields();
+        for ((name, expected), (_, actual)) in expected.iter().zip(self.actual.fields()) {
+            let marker = if *expected != actual { "  <--" } else { "" };
+            writeln!(f, "{:<8}{:<12}{}{}", name, expected, actual, marker)?;
+        }
+        Ok(())
+    }
+}
+
+// Runs a freshly loaded ROM in lockstep with a reference trace, comparing the
+// state before every instruction. The timers tick every `ticks_per_frame`
+// instructions. No keys get pressed, so the reference should be recorded
+// without input. Blank lines and lines starting with # are skipped.
+pub fn compare(
+    chip8: &mut Chip8,
+    reference: impl BufRead,
+    ticks_per_frame: usize,
+) -> Result<Comparison, String> {
+    let mut previous = None;
+    let mut instruction = 0;
+    for (index, line) in reference.lines().enumerate() {
+        let line = line.map_err(|e| format!("Cannot read reference trace: {}", e))?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let expected: TraceEntry = line.parse()?;
+        let actual = chip8.trace_entry();
+        if actual != expected {
+            return Ok(Comparison::Divergence(Divergence {
+                instruction,
+                line: index + 1,
+                previous,
+                expected,
+                actual,
+            }));
+        }
+
+        chip8.exec();
+        instruction += 1;
+        if ticks_per_frame > 0 && instruction % ticks_per_frame == 0 {
+            chip8.update_timers();
+        }
+        previous = Some(actual);
+    }
+    Ok(Comparison::Match(instruction))
+}
+
+pub struct Tracer<'a> {
+    output: Box<dyn Write + 'a>,
+    // Only instructions at these addresses get traced
+    range: Option<RangeInclusive<u16>>,
+    // Ring buffer mode: keep the last this many entries in `history` and only
+    // write them out when `dump` is called, e.g. after an error
+    capacity: Option<usize>,
+    history: VecDeque<TraceEntry>,
+}
+
+impl<'a> Tracer<'a> {
+    pub fn new(
+        output: impl Write + 'a,
+        range: Option<RangeInclusive<u16>>,
+        capacity: Option<usize>,
+    ) -> Self {
+        Tracer {
+            output: Box::new(output),
+            range,
+            capacity,
+            history: VecDeque::with_capacity(capacity.unwrap_or(0)),
+        }
+    }
+
+    pub fn trace(&mut self, entry: TraceEntry) {
+        if let Some(range) = &self.range
+            && !range.contains(&entry.pc)
+        {
+            return;
+        }
+
+        match self.capacity {
+            Some(0) => {}
+            Some(capacity) => {
+                if self.history.len() == capacity {
+                    self.history.pop_front();
+                }
+                self.history.push_back(entry);
+            }
+            // A broken trace output shouldn't stop the emulator
+            None => {
+                let _ = writeln!(self.output, "{}", entry);
+            }
+        }
+    }
+
+    // Writes out the entries kept in ring buffer mode
+    pub fn dump(&mut self) {
+        for entry in self.history.drain(..) {
+            let _ = writeln!(self.output, "{}", entry);
+        }
+        let _ = self.output.flush();
+    }
+}
+
+impl Drop for Tracer<'_> {
+    // Also covers panics inside the emulator, e.g. on stack overflows
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            self.dump();
+        } else {
+            let _ = self.output.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::SilentSpeaker;
+
+    fn entry(pc: u16) -> TraceEntry {
+        TraceEntry {
+            pc,
+            opcode: 0x7001,
+            v_registers: [0; 16],
+            i: 0x300,
+            sp: 1,
+            dt: 0,
+            st: 0x3C,
+        }
+    }
+
+    #[test]
+    fn it_formats_trace_lines() {
+        let mut entry = entry(0x204);
+        entry.v_registers[0] = 0x12;
+        entry.v_registers[0xF] = 0x01;
+        assert_eq!(
+            entry.to_string(),
+            "0204: 7001  ADD V0, 0x01          \
+             V=12000000000000000000000000000001 I=0300 SP=01 DT=00 ST=3C"
+        );
+    }
+
+    #[test]
+    fn it_parses_trace_lines() {
+        let mut entry = entry(0x204);
+        entry.v_registers[0xA] = 0xBC;
+        assert_eq!(entry.to_string().parse(), Ok(entry));
+
+        // Mnemonics are optional. Keys are case-sensitive, hex digits are not.
+        let line = "0204: 7001 V=000000000000000000000000000000ff i=0300 SP=01 DT=00 ST=3c";
+        assert!(line.parse::<TraceEntry>().is_err());
+        let line = line.replace("i=", "I=");
+        let parsed: TraceEntry = line.parse().unwrap();
+        assert_eq!(parsed.v_registers[0xF], 0xFF);
+        assert_eq!(parsed.st, 0x3C);
+    }
+
+    #[test]
+    fn it_finds_the_first_divergence_from_a_reference_trace() {
+        let mut chip8 = Chip8::new(Box::new(SilentSpeaker));
+        // LD V0, 0x12; ADD V0, 1; JP 0x202
+        chip8
+            .load_rom(vec![0x60, 0x12, 0x70, 0x01, 0x12, 0x02])
+            .unwrap();
+        let mut reference = String::from("# reference\n");
+        let mut state = chip8.trace_entry();
+        // The last line has a wrong ADD result
+        for (pc, opcode, v0) in [
+            (0x200, 0x6012, 0x00),
+            (0x202, 0x7001, 0x12),
+            (0x204, 0x1202, 0x13),
+            (0x202, 0x7001, 0x14),
+        ] {
+            state.pc = pc;
+            state.opcode = opcode;
+            state.v_registers[0] = v0;
+            reference.push_str(&format!("{}\n", state));
+        }
+
+        let Comparison::Divergence(divergence) =
+            compare(&mut chip8, reference.as_bytes(), 10).unwrap()
+        else {
+            panic!("Traces should diverge");
+        };
+        assert_eq!(divergence.instruction, 3);
+        assert_eq!(divergence.line, 5);
+        assert_eq!(divergence.previous.unwrap().pc, 0x204);
+        assert_eq!(divergence.expected.v_registers[0], 0x14);
+        assert_eq!(divergence.actual.v_registers[0], 0x13);
+        assert!(
+            divergence
+                .to_string()
+                .contains("V0      14          13  <--")
+        );
+    }
+
+    #[test]
+    fn it_only_traces_the_address_range() {
+        let mut output = Vec::new();
+        let mut tracer = Tracer::new(&mut output, Some(0x202..=0x204), None);
+        for pc in [0x200, 0x202, 0x204, 0x206] {
+            tracer.trace(entry(pc));
+        }
+        drop(tracer);
+
+        let output = String::from_utf8(output).unwrap();
+        let pcs: Vec<&str> = output.lines().map(|line| &line[..4]).collect();
+        assert_eq!(pcs, ["0202", "0204"]);
+    }
+
+    #[test]
+    fn it_keeps_the_last_entries_in_ring_buffer_mode() {
+        let mut output = Vec::new();
+        let mut tracer = Tracer::new(&mut output, None, Some(2));
+        for pc in [0x200, 0x202, 0x204] {
+            tracer.trace(entry(pc));
+        }
+        tracer.dump();
+        drop(tracer);
+
+        let output = String::from_utf8(output).unwrap();
+        let pcs: Vec<&str> = output.lines().map(|line| &line[..4]).collect();
+        assert_eq!(pcs, ["0202", "0204"]);
+    }
+}
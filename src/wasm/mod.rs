@@ -1,11 +1,21 @@
 pub mod speaker;
 
-use crate::chip8::{self, DISPLAY_HEIGHT, DISPLAY_WIDTH, NUM_KEYS, TICKS_PER_FRAME};
+use std::time::Duration;
+
+use crate::chip8::{self, FRAME_DURATION, TICKS_PER_FRAME};
+use crate::keypad::Key;
+use crate::phosphor::PhosphorFilter;
+use crate::romdb::{RomDatabase, RomInfo};
 use wasm_bindgen::prelude::*;
 
 #[wasm_bindgen]
 pub struct Chip8Emulator {
     chip8: chip8::Chip8<'static>,
+    speaker: speaker::WebSpeaker,
+    phosphor: Option<PhosphorFilter>,
+    // ROM database entry of the loaded ROM, and settings that override it
+    rom_info: Option<RomInfo>,
+    ticks_per_frame: Option<usize>,
 }
 
 #[wasm_bindgen]
@@ -13,50 +23,163 @@ impl Chip8Emulator {
     #[allow(clippy::new_without_default)]
     #[wasm_bindgen(constructor)]
     pub fn new() -> Self {
-        let speaker = Box::new(speaker::WebSpeaker::new());
+        let speaker = speaker::WebSpeaker::new();
         Chip8Emulator {
-            chip8: chip8::Chip8::new(speaker),
+            chip8: chip8::Chip8::new(Box::new(speaker.clone())),
+            speaker,
+            phosphor: None,
+            rom_info: None,
+            ticks_per_frame: None,
         }
     }
 
+    // Also applies the quirks the ROM database recommends for the ROM. Call
+    // set_quirk afterwards to override them.
     pub fn load_rom(&mut self, rom: &[u8]) -> Result<usize, JsValue> {
-        self.chip8
+        let size = self
+            .chip8
             .load_rom(rom.to_vec())
-            .map_err(|e| JsValue::from_str(&e))
+            .map_err(|e| JsValue::from_str(&e))?;
+        self.rom_info = RomDatabase::bundled().lookup(rom);
+        self.chip8.set_quirks(
+            self.rom_info
+                .as_ref()
+                .map(|info| info.quirks)
+                .unwrap_or_default(),
+        );
+        Ok(size)
     }
 
     pub fn tick(&mut self) {
-        for _ in 0..TICKS_PER_FRAME {
-            self.chip8.exec();
+        self.chip8.run_frame(self.ticks_per_frame());
+        if let Some(filter) = self.phosphor.as_mut() {
+            filter.update(self.chip8.screen(), FRAME_DURATION);
         }
-        self.chip8.update_timers();
     }
 
     pub fn key_down(&mut self, key: usize) {
-        if key < NUM_KEYS {
-            self.chip8.key_down(key);
+        // Key presses count as user gestures, which browsers require before
+        // audio can play. Failing to create audio output isn't fatal.
+        let _ = self.speaker.unlock();
+        if let Ok(key) = Key::try_from(key) {
+            self.chip8.queue_key_event(key_event(key, true));
         }
     }
 
     pub fn key_up(&mut self, key: usize) {
-        if key < NUM_KEYS {
-            self.chip8.key_up(key);
+        if let Ok(key) = Key::try_from(key) {
+            self.chip8.queue_key_event(key_event(key, false));
         }
     }
 
+    // One brightness byte per pixel, 0 for unlit and 255 for lit. Phosphor
+    // persistence adds the values in between.
     pub fn get_display_buffer(&self) -> Vec<u8> {
-        let mut buffer = vec![0u8; DISPLAY_WIDTH * DISPLAY_HEIGHT];
-        for y in 0..DISPLAY_HEIGHT {
-            for x in 0..DISPLAY_WIDTH {
-                let idx = y * DISPLAY_WIDTH + x;
-                buffer[idx] = if self.chip8.is_pixel_set(x, y) { 1 } else { 0 };
-            }
+        match &self.phosphor {
+            Some(filter) => filter
+                .intensity()
+                .iter()
+                .map(|&i| (i * 255.0) as u8)
+                .collect(),
+            None => self
+                .chip8
+                .screen()
+                .iter()
+                .map(|&pixel| if pixel { 255 } else { 0 })
+                .collect(),
         }
-        buffer
+    }
+
+    // Enables phosphor persistence with the given time constant, 0 disables it
+    pub fn set_phosphor(&mut self, time_constant_ms: u32) {
+        self.phosphor = match time_constant_ms {
+            0 => None,
+            ms => Some(PhosphorFilter::new(Duration::from_millis(ms.into()))),
+        };
+    }
+
+    pub fn rom_title(&self) -> Option<String> {
+        self.rom_info.as_ref().map(|info| info.title.clone())
+    }
+
+    // Background and foreground color as six RGB bytes, from the ROM
+    // database or black and white
+    pub fn palette(&self) -> Vec<u8> {
+        let palette = self
+            .rom_info
+            .as_ref()
+            .and_then(|info| info.palette)
+            .unwrap_or_default();
+        [palette.background, palette.foreground].concat()
+    }
+
+    // The CHIP-8 key the ROM database maps a control ("up", "down", "left",
+    // "right", "a" or "b") to, for e.g. arrow key support
+    pub fn control_key(&self, control: &str) -> Option<u8> {
+        let info = self.rom_info.as_ref()?;
+        info.keys.get(control).map(|key| key.value())
+    }
+
+    // Turns a quirk on or off by name, see quirks::QUIRK_NAMES
+    pub fn set_quirk(&mut self, name: &str, enabled: bool) -> Result<(), JsValue> {
+        let mut quirks = self.chip8.quirks();
+        quirks
+            .set(name, enabled)
+            .map_err(|e| JsValue::from_str(&e))?;
+        self.chip8.set_quirks(quirks);
+        Ok(())
+    }
+
+    pub fn ticks_per_frame(&self) -> usize {
+        self.ticks_per_frame
+            .or(self.rom_info.as_ref().and_then(|info| info.tick_rate))
+            .unwrap_or(TICKS_PER_FRAME)
+    }
+
+    // Overrides the ROM database's tick rate, 0 goes back to it
+    pub fn set_ticks_per_frame(&mut self, ticks: usize) {
+        self.ticks_per_frame = Some(ticks).filter(|&ticks| ticks > 0);
+    }
+
+    // For drawing a visual sound indicator, e.g. for muted players
+    pub fn is_sound_active(&self) -> bool {
+        self.chip8.is_sound_active()
+    }
+
+    pub fn take_screen_dirty(&mut self) -> bool {
+        self.chip8.take_screen_dirty()
+    }
+
+    // Call from a user gesture handler (e.g. a click) to enable sound before
+    // the first key press
+    pub fn resume_audio(&mut self) -> Result<(), JsValue> {
+        self.speaker.unlock()
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.speaker.set_volume(volume);
+    }
+
+    // Tone frequency in Hz
+    pub fn set_pitch(&mut self, frequency: f32) {
+        self.speaker.set_pitch(frequency);
     }
 
     pub fn reset(&mut self) {
-        let speaker = Box::new(speaker::WebSpeaker::new());
-        self.chip8 = chip8::Chip8::new(speaker);
+        self.chip8 = chip8::Chip8::new(Box::new(self.speaker.clone()));
+        self.rom_info = None;
+        // Silence a tone that was playing when the old machine went away
+        chip8::Speaker::beep(&mut self.speaker, false);
+        if let Some(filter) = self.phosphor.as_mut() {
+            *filter = PhosphorFilter::new(filter.time_constant());
+        }
+    }
+}
+
+fn key_event(key: Key, pressed: bool) -> chip8::KeyEvent {
+    chip8::KeyEvent {
+        key,
+        pressed,
+        timestamp: js_sys::Date::now() as u64,
     }
 }
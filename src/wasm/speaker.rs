@@ -1,16 +1,245 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use wasm_bindgen::JsValue;
+use web_sys::{AudioContext, GainNode, OscillatorNode, OscillatorType};
+
 use crate::chip8;
+use crate::synth::{DEFAULT_PITCH, DEFAULT_VOLUME};
+
+// The sound source driven by the speaker, so the on/off logic can be tested
+// without a browser
+pub trait Oscillator {
+    fn start(&mut self);
+    fn stop(&mut self);
+    fn set_frequency(&mut self, frequency: f32);
+    fn set_volume(&mut self, volume: f32);
+}
+
+// Square wave oscillator routed through a gain node. Oscillator nodes can
+// only be started once, so it runs continuously and the gain acts as switch.
+struct WebOscillator {
+    // Keeps the audio graph alive
+    _context: AudioContext,
+    oscillator: OscillatorNode,
+    gain: GainNode,
+    volume: f32,
+    playing: bool,
+}
+
+impl WebOscillator {
+    fn new() -> Result<Self, JsValue> {
+        let context = AudioContext::new()?;
+        // Contexts created outside a user gesture start out suspended
+        let _ = context.resume()?;
 
-pub struct WebSpeaker {}
+        let oscillator = context.create_oscillator()?;
+        oscillator.set_type(OscillatorType::Square);
+
+        let gain = context.create_gain()?;
+        gain.gain().set_value(0.0);
+
+        oscillator.connect_with_audio_node(&gain)?;
+        gain.connect_with_audio_node(&context.destination())?;
+        oscillator.start()?;
+
+        Ok(WebOscillator {
+            _context: context,
+            oscillator,
+            gain,
+            volume: DEFAULT_VOLUME,
+            playing: false,
+        })
+    }
+}
+
+impl Oscillator for WebOscillator {
+    fn start(&mut self) {
+        self.playing = true;
+        self.gain.gain().set_value(self.volume);
+    }
+
+    fn stop(&mut self) {
+        self.playing = false;
+        self.gain.gain().set_value(0.0);
+    }
+
+    fn set_frequency(&mut self, frequency: f32) {
+        self.oscillator.frequency().set_value(frequency);
+    }
+
+    fn set_volume(&mut self, volume: f32) {
+        self.volume = volume;
+        if self.playing {
+            self.gain.gain().set_value(volume);
+        }
+    }
+}
+
+struct State {
+    oscillator: Option<Box<dyn Oscillator>>,
+    playing: bool,
+    frequency: f32,
+    volume: f32,
+}
+
+// Cheap to clone handle, so the emulator can keep adjusting the speaker
+// after handing a copy to `Chip8`
+#[derive(Clone)]
+pub struct WebSpeaker {
+    state: Rc<RefCell<State>>,
+}
 
 impl WebSpeaker {
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
-        WebSpeaker {}
+        WebSpeaker {
+            state: Rc::new(RefCell::new(State {
+                oscillator: None,
+                playing: false,
+                frequency: DEFAULT_PITCH,
+                volume: DEFAULT_VOLUME,
+            })),
+        }
+    }
+
+    // Browsers only allow audio after a user gesture, so the audio context
+    // gets created the first time this is called from an event handler
+    pub fn unlock(&self) -> Result<(), JsValue> {
+        if self.state.borrow().oscillator.is_none() {
+            self.attach(Box::new(WebOscillator::new()?));
+        }
+        Ok(())
+    }
+
+    pub fn attach(&self, mut oscillator: Box<dyn Oscillator>) {
+        let mut state = self.state.borrow_mut();
+        oscillator.set_frequency(state.frequency);
+        oscillator.set_volume(state.volume);
+        if state.playing {
+            oscillator.start();
+        }
+        state.oscillator = Some(oscillator);
+    }
+
+    pub fn set_pitch(&self, frequency: f32) {
+        let mut state = self.state.borrow_mut();
+        state.frequency = frequency;
+        if let Some(oscillator) = state.oscillator.as_mut() {
+            oscillator.set_frequency(frequency);
+        }
+    }
+
+    pub fn set_volume(&self, volume: f32) {
+        let mut state = self.state.borrow_mut();
+        state.volume = volume.clamp(0.0, 1.0);
+        let volume = state.volume;
+        if let Some(oscillator) = state.oscillator.as_mut() {
+            oscillator.set_volume(volume);
+        }
     }
 }
 
 impl chip8::Speaker for WebSpeaker {
-    fn beep(&mut self, _status: bool) {
-        // TODO: Implement Web Audio API
+    fn beep(&mut self, status: bool) {
+        let mut state = self.state.borrow_mut();
+        if state.playing == status {
+            return;
+        }
+
+        state.playing = status;
+        if let Some(oscillator) = state.oscillator.as_mut() {
+            if status {
+                oscillator.start();
+            } else {
+                oscillator.stop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::Speaker;
+
+    #[derive(Debug, PartialEq)]
+    enum Call {
+        Start,
+        Stop,
+        Frequency(f32),
+        Volume(f32),
+    }
+
+    struct MockOscillator {
+        calls: Rc<RefCell<Vec<Call>>>,
+    }
+
+    impl Oscillator for MockOscillator {
+        fn start(&mut self) {
+            self.calls.borrow_mut().push(Call::Start);
+        }
+
+        fn stop(&mut self) {
+            self.calls.borrow_mut().push(Call::Stop);
+        }
+
+        fn set_frequency(&mut self, frequency: f32) {
+            self.calls.borrow_mut().push(Call::Frequency(frequency));
+        }
+
+        fn set_volume(&mut self, volume: f32) {
+            self.calls.borrow_mut().push(Call::Volume(volume));
+        }
+    }
+
+    fn speaker_with_mock() -> (WebSpeaker, Rc<RefCell<Vec<Call>>>) {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let speaker = WebSpeaker::new();
+        speaker.attach(Box::new(MockOscillator {
+            calls: Rc::clone(&calls),
+        }));
+        calls.borrow_mut().clear();
+        (speaker, calls)
+    }
+
+    #[test]
+    fn it_only_toggles_the_oscillator_when_the_status_changes() {
+        let (mut speaker, calls) = speaker_with_mock();
+        speaker.beep(false);
+        speaker.beep(true);
+        speaker.beep(true);
+        speaker.beep(false);
+        assert_eq!(*calls.borrow(), vec![Call::Start, Call::Stop]);
+    }
+
+    #[test]
+    fn it_forwards_pitch_and_volume_changes() {
+        let (speaker, calls) = speaker_with_mock();
+        speaker.set_pitch(880.0);
+        speaker.set_volume(2.0);
+        assert_eq!(
+            *calls.borrow(),
+            vec![Call::Frequency(880.0), Call::Volume(1.0)]
+        );
+    }
+
+    #[test]
+    fn a_late_oscillator_picks_up_the_current_state() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut speaker = WebSpeaker::new();
+        speaker.set_pitch(220.0);
+        speaker.beep(true);
+        speaker.attach(Box::new(MockOscillator {
+            calls: Rc::clone(&calls),
+        }));
+        assert_eq!(
+            *calls.borrow(),
+            vec![
+                Call::Frequency(220.0),
+                Call::Volume(DEFAULT_VOLUME),
+                Call::Start
+            ]
+        );
     }
 }
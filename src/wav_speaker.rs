@@ -0,0 +1,169 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::ops::Range;
+use std::rc::Rc;
+
+use crate::chip8::{AUDIO_PATTERN_SIZE, FRAME_DURATION, Speaker};
+use crate::synth::{DEFAULT_SAMPLE_RATE, Synth, ToneSettings};
+
+pub const WAV_SAMPLE_RATE: u32 = DEFAULT_SAMPLE_RATE;
+
+struct State {
+    synth: Synth,
+    samples: Vec<i16>,
+    frames: u64,
+    // Frames during which the sound timer was active, as half-open ranges
+    tones: Vec<Range<u64>>,
+}
+
+// Renders the sound output into memory instead of playing it. Time is
+// measured in emulated frames: every `beep` call (one per `update_timers`)
+// adds exactly one frame worth of samples, no matter how fast the emulator
+// runs. Cloning yields another handle to the same recording.
+#[derive(Clone)]
+pub struct WavSpeaker {
+    state: Rc<RefCell<State>>,
+}
+
+impl WavSpeaker {
+    pub fn new(settings: ToneSettings) -> Self {
+        WavSpeaker {
+            state: Rc::new(RefCell::new(State {
+                synth: Synth::new(WAV_SAMPLE_RATE, settings),
+                samples: Vec::new(),
+                frames: 0,
+                tones: Vec::new(),
+            })),
+        }
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.state.borrow().frames
+    }
+
+    pub fn tones(&self) -> Vec<Range<u64>> {
+        self.state.borrow().tones.clone()
+    }
+
+    pub fn samples(&self) -> Vec<i16> {
+        self.state.borrow().samples.clone()
+    }
+
+    // Writes the recording as 16 bit mono PCM WAV
+    pub fn write_wav<W: Write>(&self, mut writer: W) -> Result<(), String> {
+        let state = self.state.borrow();
+        let data_size = (state.samples.len() * 2) as u32;
+
+        let mut header = Vec::with_capacity(44);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(36 + data_size).to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes()); // fmt chunk size
+        header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        header.extend_from_slice(&1u16.to_le_bytes()); // mono
+        header.extend_from_slice(&WAV_SAMPLE_RATE.to_le_bytes());
+        header.extend_from_slice(&(WAV_SAMPLE_RATE * 2).to_le_bytes()); // byte rate
+        header.extend_from_slice(&2u16.to_le_bytes()); // block align
+        header.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&data_size.to_le_bytes());
+
+        let data: Vec<u8> = state.samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        writer
+            .write_all(&header)
+            .and_then(|_| writer.write_all(&data))
+            .and_then(|_| writer.flush())
+            .map_err(|e| format!("Cannot write WAV: {}", e))
+    }
+}
+
+impl State {
+    // Number of samples from the start of the recording to the end of `frame`,
+    // so rounding errors don't add up over time
+    fn samples_until(frame: u64) -> usize {
+        (frame as f64 * FRAME_DURATION.as_secs_f64() * WAV_SAMPLE_RATE as f64).round() as usize
+    }
+}
+
+impl Speaker for WavSpeaker {
+    fn beep(&mut self, status: bool) {
+        let mut state = self.state.borrow_mut();
+        let frame = state.frames;
+
+        if status {
+            match state.tones.last_mut() {
+                Some(tone) if tone.end == frame => tone.end += 1,
+                _ => state.tones.push(frame..frame + 1),
+            }
+        }
+
+        state.synth.set_gate(status);
+        let count = State::samples_until(frame + 1) - State::samples_until(frame);
+        let mut buffer = vec![0.0; count];
+        state.synth.fill(&mut buffer);
+        state
+            .samples
+            .extend(buffer.iter().map(|&s| (s * i16::MAX as f32) as i16));
+        state.frames += 1;
+    }
+
+    fn set_pattern(&mut self, pattern: &[u8; AUDIO_PATTERN_SIZE], rate: f32) {
+        self.state.borrow_mut().synth.set_pattern(*pattern, rate);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::{Chip8, TICKS_PER_FRAME};
+
+    fn run_frames(rom: Vec<u8>, frames: usize) -> WavSpeaker {
+        let speaker = WavSpeaker::new(ToneSettings::default());
+        let mut chip8 = Chip8::new(Box::new(speaker.clone()));
+        chip8.load_rom(rom).unwrap();
+        for _ in 0..frames {
+            chip8.run_frame(TICKS_PER_FRAME);
+        }
+        speaker
+    }
+
+    #[test]
+    fn it_records_how_long_the_sound_timer_runs() {
+        // LD V0, 30; LD ST, V0; JP 0x204
+        let speaker = run_frames(vec![0x60, 0x1E, 0xF0, 0x18, 0x12, 0x04], 60);
+        assert_eq!(speaker.frames(), 60);
+        assert_eq!(speaker.tones(), vec![0..30]);
+    }
+
+    #[test]
+    fn it_renders_samples_in_emulated_time() {
+        let speaker = run_frames(vec![0x60, 0x1E, 0xF0, 0x18, 0x12, 0x04], 60);
+        let samples = speaker.samples();
+
+        // One second of audio (frames are slightly longer than 1/60 s), with
+        // sound during the first half only
+        assert_eq!(samples.len(), State::samples_until(60));
+        assert_eq!(samples.len(), 48001);
+        assert!(samples[..24000].iter().any(|&s| s != 0));
+        assert!(samples[24500..].iter().all(|&s| s == 0));
+    }
+
+    #[test]
+    fn the_audio_test_rom_beeps_for_one_second() {
+        let rom = include_bytes!("../roms/chip8-test-rom-with-audio.ch8").to_vec();
+        let speaker = run_frames(rom, 300);
+        assert_eq!(speaker.tones(), vec![20..80]);
+    }
+
+    #[test]
+    fn it_writes_a_wav_file() {
+        let speaker = run_frames(vec![0x12, 0x00], 3);
+        let mut wav = Vec::new();
+        speaker.write_wav(&mut wav).unwrap();
+
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(u32::from_le_bytes(wav[24..28].try_into().unwrap()), 48000);
+        assert_eq!(wav.len(), 44 + speaker.samples().len() * 2);
+    }
+}
@@ -0,0 +1,42 @@
+// Runs the emulator binary headless and checks what it writes to stdout
+use std::process::{Command, Output};
+
+// One RGB24 frame at scale 1
+const FRAME_SIZE: usize = 64 * 32 * 3;
+
+fn headless(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_chip-8"))
+        .args(["headless", "--frames", "3", "--scale", "1"])
+        .args(args)
+        .arg("roms/test_opcode.ch8")
+        .output()
+        .expect("The emulator runs")
+}
+
+#[test]
+fn it_writes_only_frames_to_stdout() {
+    let output = headless(&["--raw-frames", "-"]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout.len(), 3 * FRAME_SIZE);
+}
+
+#[test]
+fn it_writes_only_the_wav_file_to_stdout() {
+    let output = headless(&["--wav", "-"]);
+    assert!(output.status.success());
+    assert!(output.stdout.starts_with(b"RIFF"));
+    // The RIFF chunk size covers everything after the first 8 bytes
+    let size = u32::from_le_bytes(output.stdout[4..8].try_into().unwrap());
+    assert_eq!(output.stdout.len(), size as usize + 8);
+}
+
+#[test]
+fn it_rejects_several_outputs_on_stdout() {
+    let output = headless(&["--raw-frames", "-", "--record", "-"]);
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    let output = headless(&["--wav", "-", "--record", "-"]);
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("can write to stdout"));
+}